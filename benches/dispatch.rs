@@ -0,0 +1,74 @@
+//! Benchmarks of the dispatch loop on small scripts.
+//!
+//!     cargo bench --bench dispatch
+//!
+//! Lox has no loops or functions yet, so every script is unrolled into
+//! straight-line code, kept under the 256 constants a chunk can hold.
+
+use rustox::chunk::Chunk;
+use rustox::compiler::Compiler;
+use rustox::vm::{InterpretResult, Vm};
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 20000;
+
+fn compile(source: &str) -> Chunk {
+    let mut compiler = Compiler::new(source);
+    assert!(!compiler.compile(Chunk::new()), "Benchmark script failed to compile.");
+    compiler.take_chunk()
+}
+
+fn bench(name: &str, source: &str) {
+    let mut total = Duration::ZERO;
+    let mut instructions = 0;
+    for _ in 0..RUNS {
+        let chunk = compile(source);
+        instructions = chunk.len();
+        let mut vm = Vm::new();
+
+        let start = Instant::now();
+        let result = vm.interpret(chunk);
+        total += start.elapsed();
+
+        assert_eq!(result, InterpretResult::Ok);
+    }
+    println!(
+        "{:<14} {:>10.2?} / run ({} bytes of code)",
+        name,
+        total / RUNS,
+        instructions
+    );
+}
+
+/// fib(30), one unrolled iteration at a time
+fn fib() -> String {
+    let mut source = String::from("var a = 0;\nvar b = 1;\n");
+    for _ in 0..30 {
+        source += "var t = a + b;\nvar a = b;\nvar b = t;\n";
+    }
+    source
+}
+
+/// a counting loop accumulating a sum, unrolled
+fn counting_loop() -> String {
+    let mut source = String::from("var i = 0;\nvar sum = 0;\n");
+    for _ in 0..28 {
+        source += "var i = i + 1;\nvar sum = sum + i * 2 - 1;\n";
+    }
+    source
+}
+
+/// repeated concatenation onto a growing string
+fn string_concat() -> String {
+    let mut source = String::from("var s = \"\";\n");
+    for _ in 0..80 {
+        source += "var s = s + \"ab\";\n";
+    }
+    source
+}
+
+fn main() {
+    bench("unrolled-fib", &fib());
+    bench("unrolled-sum", &counting_loop());
+    bench("string-concat", &string_concat());
+}
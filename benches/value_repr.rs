@@ -0,0 +1,75 @@
+//! Compares the stack value representations.
+//!
+//!     cargo bench --bench value_repr
+//!     cargo bench --bench value_repr --features nan-boxing
+
+use rustox::chunk::Chunk;
+use rustox::compiler::Compiler;
+use rustox::vm::{InterpretResult, Vm};
+use std::time::{Duration, Instant};
+
+// scripts stay under the 256 constants a chunk can hold,
+// so the work comes from repeating them
+const RUNS: u32 = 20000;
+
+fn compile(source: &str) -> Chunk {
+    let mut compiler = Compiler::new(source);
+    assert!(!compiler.compile(Chunk::new()), "Benchmark script failed to compile.");
+    compiler.take_chunk()
+}
+
+fn bench(name: &str, source: &str) {
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let chunk = compile(source);
+        let mut vm = Vm::new();
+
+        let start = Instant::now();
+        let result = vm.interpret(chunk);
+        total += start.elapsed();
+
+        assert_eq!(result, InterpretResult::Ok);
+    }
+    println!("{:<12} {:>10.2?} / run", name, total / RUNS);
+}
+
+/// a long chain of arithmetic, mostly pushing and popping numbers.
+/// operands are read from globals so that the compiler cannot fold them away
+fn arithmetic() -> String {
+    let mut source = String::from("var x = 1;\nvar y = 2;\n");
+    for i in 0..20 {
+        source += &format!("var a{} = x + y * 3 - x / 5 + {} % y - (y ** x ~/ 3);\n", i, i);
+    }
+    source
+}
+
+/// reference counted string values
+fn strings() -> String {
+    let mut source = String::from("var s = \"x\";\n");
+    for i in 0..40 {
+        source += &format!("var s{} = s + \"y\" + {} + s;\n", i, i);
+    }
+    source
+}
+
+/// map literals and index expressions
+fn maps() -> String {
+    let mut source = String::from("var m = {\"a\": 1, \"b\": 2};\n");
+    for i in 0..30 {
+        source += &format!("m[{}] = m[\"a\"] + m[\"b\"] * {};\n", i % 16, i);
+    }
+    source
+}
+
+fn main() {
+    let repr = if cfg!(feature = "nan-boxing") {
+        "nan-boxing"
+    } else {
+        "enum"
+    };
+    println!("value representation: {}", repr);
+
+    bench("arithmetic", &arithmetic());
+    bench("strings", &strings());
+    bench("maps", &maps());
+}
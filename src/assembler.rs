@@ -0,0 +1,298 @@
+//! A textual assembler, the inverse of the disassembler.
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! .constants
+//!     "hello"         ; 0, a string
+//!     @greeting       ; 1, an identifier
+//!     1.5             ; 2
+//!     True            ; booleans and Nil are spelled like `Value` displays them
+//! .code
+//!     .line 1         ; source line of the instructions that follow
+//!     Constant 0 '"hello"'
+//!     DefineGlobal 1
+//! end:
+//!     Return
+//! ```
+//!
+//! Instructions use the mnemonics printed by `Chunk::disas_opcode`, and a trailing
+//! `'...'` annotation as printed by the disassembler is ignored. Labels mark an
+//! offset in the code. No instruction takes a code offset yet, as there are no
+//! jumps, so labels only mark places and an operand cannot name one.
+
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+use crate::value::Value;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line of the assembly source
+    pub line: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        AsmError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+enum Section {
+    None,
+    Constants,
+    Code,
+}
+
+impl Chunk {
+    /// Assembles a chunk from its textual form.
+    /// The result is not verified; use `Chunk::verify` before running untrusted text.
+    pub fn assemble(source: &str) -> Result<Chunk, AsmError> {
+        let mut code = Vec::new();
+        let mut lines = Vec::new();
+        let mut values = Vec::new();
+
+        let mut labels = HashSet::new();
+        let mut section = Section::None;
+        let mut source_line = 1;
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let text = strip_comment(text).trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            match text {
+                ".constants" => {
+                    section = Section::Constants;
+                    continue;
+                }
+                ".code" => {
+                    section = Section::Code;
+                    continue;
+                }
+                _ => (),
+            }
+
+            match section {
+                Section::None => {
+                    return Err(AsmError::new(line, "Expected '.constants' or '.code'."))
+                }
+                Section::Constants => {
+                    if values.len() > u8::MAX as usize {
+                        return Err(AsmError::new(line, "Too many constants in one chunk."));
+                    }
+                    values.push(parse_constant(text, line)?);
+                }
+                Section::Code => {
+                    let mut text = text;
+                    if let Some((label, rest)) = split_label(text) {
+                        if !labels.insert(label) {
+                            return Err(AsmError::new(line, format!("Duplicate label '{}'.", label)));
+                        }
+                        text = rest.trim();
+                        if text.is_empty() {
+                            continue;
+                        }
+                    }
+
+                    if let Some(n) = text.strip_prefix(".line") {
+                        source_line = n
+                            .trim()
+                            .parse::<u32>()
+                            .map_err(|_| AsmError::new(line, "Expected a line number after '.line'."))?;
+                        continue;
+                    }
+
+                    let (mnemonic, operand) = match text.split_once(char::is_whitespace) {
+                        Some((m, rest)) => (m, strip_annotation(rest.trim())),
+                        None => (text, ""),
+                    };
+                    let opcode = parse_mnemonic(mnemonic)
+                        .ok_or_else(|| AsmError::new(line, format!("Unknown instruction '{}'.", mnemonic)))?;
+
+                    code.push(opcode.into());
+                    lines.push(source_line);
+
+                    if opcode.len() == 1 {
+                        if !operand.is_empty() {
+                            return Err(AsmError::new(line, format!("{:?} takes no operand.", opcode)));
+                        }
+                        continue;
+                    }
+
+                    let value = if operand.is_empty() {
+                        return Err(AsmError::new(line, format!("{:?} expects an operand.", opcode)));
+                    } else if operand.starts_with(|c: char| c.is_ascii_digit()) {
+                        operand
+                            .parse::<usize>()
+                            .map_err(|_| AsmError::new(line, format!("Invalid operand '{}'.", operand)))?
+                    } else {
+                        return Err(AsmError::new(
+                            line,
+                            format!("Invalid operand '{}'. No instruction takes a label yet.", operand),
+                        ));
+                    };
+                    write_operand(&mut code, &mut lines, value, source_line, line)?;
+                }
+            }
+        }
+
+        Ok(Chunk::from_parts(code, lines, values))
+    }
+}
+
+/// every operand is a single byte
+fn write_operand(
+    code: &mut Vec<u8>,
+    lines: &mut Vec<u32>,
+    value: usize,
+    source_line: u32,
+    line: usize,
+) -> Result<(), AsmError> {
+    if value > u8::MAX as usize {
+        return Err(AsmError::new(line, format!("Operand {} is out of range.", value)));
+    }
+    code.push(value as u8);
+    lines.push(source_line);
+    Ok(())
+}
+
+fn parse_mnemonic(name: &str) -> Option<Opcode> {
+    (0..=u8::MAX)
+        .map_while(Opcode::decode)
+        .find(|opcode| format!("{:?}", opcode) == name)
+}
+
+fn parse_constant(text: &str, line: usize) -> Result<Value, AsmError> {
+    let text = strip_annotation(text);
+
+    if let Some(rest) = text.strip_prefix('"') {
+        return match rest.strip_suffix('"') {
+            Some(s) => Ok(Value::String(Rc::new(s.to_owned()))),
+            None => Err(AsmError::new(line, "Unterminated string constant.")),
+        };
+    }
+    if let Some(ident) = text.strip_prefix('@') {
+        return Ok(Value::Ident(Rc::new(ident.to_owned())));
+    }
+
+    match text {
+        "Nil" | "nil" => Ok(Value::Nil),
+        "True" | "true" => Ok(Value::Bool(true)),
+        "False" | "false" => Ok(Value::Bool(false)),
+        _ => text
+            .parse::<f64>()
+            .map(Value::Number)
+            .map_err(|_| AsmError::new(line, format!("Invalid constant '{}'.", text))),
+    }
+}
+
+/// strips a `;` comment, leaving semicolons inside strings alone
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+/// strips the `'...'` annotation the disassembler prints after a constant operand
+fn strip_annotation(text: &str) -> &str {
+    if text.starts_with('"') {
+        return text;
+    }
+    match text.find('\'') {
+        Some(i) => text[..i].trim_end(),
+        None => text,
+    }
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    let is_name = !label.is_empty()
+        && !label.starts_with(|c: char| c.is_ascii_digit())
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_name {
+        Some((label, rest))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble() {
+        let chunk = Chunk::assemble(
+            r#"
+            .constants
+                "a;b"       ; 0
+                @m          ; 1
+                2.5
+            .code
+                .line 3
+                Constant 0 '"a;b"'
+                DefineGlobal 1 '@m'
+            done:
+                .line 4
+                Return
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(chunk.code(), &[1, 0, 18, 1, 0]);
+        assert_eq!(chunk.lines, vec![3, 3, 3, 3, 4]);
+        assert_eq!(
+            chunk.constants(),
+            &[
+                Value::String(Rc::new("a;b".to_owned())),
+                Value::Ident(Rc::new("m".to_owned())),
+                Value::Number(2.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        // labels only mark places until there are jumps to take them as operands
+        let chunk = Chunk::assemble(".code\nstart: Nil\nPop\nend:\nReturn").unwrap();
+        assert_eq!(chunk.code(), &[7, 17, 0]);
+
+        let err = Chunk::assemble(".code\nend:\nNil\nend: Return").unwrap_err();
+        assert_eq!(err, AsmError::new(4, "Duplicate label 'end'."));
+        let err = Chunk::assemble(".code\nNil\nCall end\nend:\nReturn").unwrap_err();
+        assert_eq!(err.line, 3);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Chunk::assemble("Return").unwrap_err().line, 1);
+        assert_eq!(Chunk::assemble(".code\n\nFrobnicate").unwrap_err().line, 3);
+        assert!(Chunk::assemble(".code\nConstant").is_err());
+        assert!(Chunk::assemble(".code\nAdd 1").is_err());
+        assert!(Chunk::assemble(".code\nBuildMap 255").is_ok());
+        assert!(Chunk::assemble(".code\nConstant 256").is_err());
+        assert!(Chunk::assemble(".constants\n\"open").is_err());
+    }
+}
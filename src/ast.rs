@@ -0,0 +1,743 @@
+//! A syntax tree of Lox programs, for passes that need to see a whole
+//! program before emitting code.
+//!
+//! `ast::parse` is a Pratt parser driven by a table of parse rules, one per
+//! token type. `Compiler` compiles scripts by parsing them with it and handing
+//! the tree to `Codegen`. Every node carries the span of source it was parsed from.
+
+use crate::opcode::Precedence;
+use crate::parser::{Parser, SyntaxError};
+use crate::token::{Token, TokenType};
+use std::fmt::Display;
+
+/// A range of source, in byte offsets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    /// line of the first token
+    pub line: u32,
+    /// line of the last token
+    pub end_line: u32,
+}
+
+impl Span {
+    fn of(token: &Token) -> Span {
+        Span {
+            start: token.start,
+            end: token.start + token.lexeme.len(),
+            line: token.line,
+            end_line: token.line,
+        }
+    }
+
+    /// the span from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            end_line: other.end_line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    FloorDivide,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Lesser,
+    LesserEqual,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn from_token(token_type: TokenType) -> BinaryOp {
+        use BinaryOp::*;
+
+        match token_type {
+            TokenType::Plus => Add,
+            TokenType::Minus => Subtract,
+            TokenType::Star => Multiply,
+            TokenType::Slash => Divide,
+            TokenType::Percent => Modulo,
+            TokenType::StarStar => Power,
+            TokenType::TildeSlash => FloorDivide,
+            TokenType::Ampersand => BitAnd,
+            TokenType::Pipe => BitOr,
+            TokenType::Caret => BitXor,
+            TokenType::LesserLesser => ShiftLeft,
+            TokenType::GreaterGreater => ShiftRight,
+            TokenType::EqualEqual => Equal,
+            TokenType::BangEqual => NotEqual,
+            TokenType::Greater => Greater,
+            TokenType::GreaterEqual => GreaterEqual,
+            TokenType::Lesser => Lesser,
+            TokenType::LesserEqual => LesserEqual,
+            TokenType::And => And,
+            TokenType::Or => Or,
+            _ => panic!("Invalid binary operator token {:?}", token_type),
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        use BinaryOp::*;
+
+        match self {
+            Add => "+",
+            Subtract => "-",
+            Multiply => "*",
+            Divide => "/",
+            Modulo => "%",
+            Power => "**",
+            FloorDivide => "~/",
+            BitAnd => "&",
+            BitOr => "|",
+            BitXor => "^",
+            ShiftLeft => "<<",
+            ShiftRight => ">>",
+            Equal => "==",
+            NotEqual => "!=",
+            Greater => ">",
+            GreaterEqual => ">=",
+            Lesser => "<",
+            LesserEqual => "<=",
+            And => "and",
+            Or => "or",
+        }
+    }
+
+    pub fn precedence(&self) -> Precedence {
+        Precedence::of_infix(match self {
+            BinaryOp::Add => TokenType::Plus,
+            BinaryOp::Subtract => TokenType::Minus,
+            BinaryOp::Multiply => TokenType::Star,
+            BinaryOp::Divide => TokenType::Slash,
+            BinaryOp::Modulo => TokenType::Percent,
+            BinaryOp::Power => TokenType::StarStar,
+            BinaryOp::FloorDivide => TokenType::TildeSlash,
+            BinaryOp::BitAnd => TokenType::Ampersand,
+            BinaryOp::BitOr => TokenType::Pipe,
+            BinaryOp::BitXor => TokenType::Caret,
+            BinaryOp::ShiftLeft => TokenType::LesserLesser,
+            BinaryOp::ShiftRight => TokenType::GreaterGreater,
+            BinaryOp::Equal => TokenType::EqualEqual,
+            BinaryOp::NotEqual => TokenType::BangEqual,
+            BinaryOp::Greater => TokenType::Greater,
+            BinaryOp::GreaterEqual => TokenType::GreaterEqual,
+            BinaryOp::Lesser => TokenType::Lesser,
+            BinaryOp::LesserEqual => TokenType::LesserEqual,
+            BinaryOp::And => TokenType::And,
+            BinaryOp::Or => TokenType::Or,
+        })
+    }
+}
+
+impl Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(f64),
+    /// the contents of a string literal, without its quotes
+    String(String),
+    Bool(bool),
+    Nil,
+    Variable(String),
+    Grouping(Box<Expr>),
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Map(Vec<(Expr, Expr)>),
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    SetIndex {
+        target: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Print(Expr),
+    Expression(Expr),
+    Var {
+        name: String,
+        name_span: Span,
+        initializer: Option<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+    /// the whole source. `end_line` is the line the source ends on
+    pub span: Span,
+}
+
+/// Parses a program, reporting errors to stderr like `Compiler::compile`.
+/// Returns `None` if there were any.
+pub fn parse(source: &str) -> Option<Program> {
+    parse_with(Parser::new(source)).ok()
+}
+
+/// Parses a program without printing anything, returning the errors instead
+pub fn parse_quietly(source: &str) -> Result<Program, Vec<SyntaxError>> {
+    let mut parser = Parser::new(source);
+    parser.print_errors = false;
+    parse_with(parser)
+}
+
+pub(crate) fn parse_with(parser: Parser) -> Result<Program, Vec<SyntaxError>> {
+    let mut parser = AstParser {
+        parser,
+        rules: init_rules(),
+    };
+
+    let mut statements = Vec::new();
+    while !parser.parser.match_token(TokenType::Eof) {
+        if let Some(stmt) = parser.declaration() {
+            statements.push(stmt);
+        }
+    }
+
+    let eof = &parser.parser.previous;
+    let span = Span {
+        start: 0,
+        end: eof.start,
+        line: 1,
+        end_line: eof.line,
+    };
+
+    if parser.parser.had_error {
+        Err(parser.parser.errors)
+    } else {
+        Ok(Program { statements, span })
+    }
+}
+
+struct AstParser<'src> {
+    parser: Parser<'src>,
+    rules: Vec<ParseRule<'src>>,
+}
+
+type PrefixFn<'src> = fn(&mut AstParser<'src>, bool) -> Expr;
+type InfixFn<'src> = fn(&mut AstParser<'src>, Expr, bool) -> Expr;
+
+struct ParseRule<'src> {
+    prefix: Option<PrefixFn<'src>>,
+    infix: Option<InfixFn<'src>>,
+    precedence: Precedence,
+}
+
+fn init_rules<'src>() -> Vec<ParseRule<'src>> {
+    use TokenType::*;
+
+    let mut rules = Vec::with_capacity(54);
+    let mut set = |token, prefix: Option<PrefixFn<'src>>, infix: Option<InfixFn<'src>>| {
+        assert_eq!(token as usize, rules.len());
+        rules.push(ParseRule {
+            prefix,
+            infix,
+            precedence: Precedence::of_infix(token),
+        });
+    };
+
+    set(LeftParen, Some(AstParser::grouping), Some(AstParser::call));
+    set(RightParen, None, None);
+    set(LeftBrace, Some(AstParser::map), None);
+    set(RightBrace, None, None);
+    set(LeftBracket, None, Some(AstParser::index));
+    set(RightBracket, None, None);
+    set(Colon, None, None);
+    set(Comma, None, None);
+    set(Dot, None, None);
+    set(Minus, Some(AstParser::unary), Some(AstParser::binary));
+    set(Plus, None, Some(AstParser::binary));
+    set(Percent, None, Some(AstParser::binary));
+    set(Semicolon, None, None);
+    set(Slash, None, Some(AstParser::binary));
+    set(Star, None, Some(AstParser::binary));
+    set(Ampersand, None, Some(AstParser::binary));
+    set(Pipe, None, Some(AstParser::binary));
+    set(Caret, None, Some(AstParser::binary));
+    set(Tilde, Some(AstParser::unary), None);
+
+    set(Bang, Some(AstParser::unary), None);
+    set(BangEqual, None, Some(AstParser::binary));
+    set(Equal, None, None);
+    set(EqualEqual, None, Some(AstParser::binary));
+    set(Greater, None, Some(AstParser::binary));
+    set(GreaterEqual, None, Some(AstParser::binary));
+    set(Lesser, None, Some(AstParser::binary));
+    set(LesserEqual, None, Some(AstParser::binary));
+    set(StarStar, None, Some(AstParser::binary));
+    set(TildeSlash, None, Some(AstParser::binary));
+    set(LesserLesser, None, Some(AstParser::binary));
+    set(GreaterGreater, None, Some(AstParser::binary));
+
+    set(Identifier, Some(AstParser::variable), None);
+    set(String, Some(AstParser::string), None);
+    set(Number, Some(AstParser::number), None);
+
+    set(And, None, Some(AstParser::binary));
+    set(Assert, None, None);
+    set(Class, None, None);
+    set(Else, None, None);
+    set(False, Some(AstParser::literal), None);
+    set(For, None, None);
+    set(Fun, None, None);
+    set(If, None, None);
+    set(Nil, Some(AstParser::literal), None);
+    set(Or, None, Some(AstParser::binary));
+    set(Print, None, None);
+    set(Return, None, None);
+    set(Super, None, None);
+    set(This, None, None);
+    set(True, Some(AstParser::literal), None);
+    set(Var, None, None);
+    set(While, None, None);
+
+    set(Comment, None, None);
+    set(Error, None, None);
+    set(Eof, None, None);
+
+    rules
+}
+
+impl<'src> AstParser<'src> {
+    fn get_rule(&self, ttype: TokenType) -> &ParseRule<'src> {
+        &self.rules[ttype as usize]
+    }
+
+    fn previous_span(&self) -> Span {
+        Span::of(&self.parser.previous)
+    }
+
+    /// a placeholder for an expression that failed to parse
+    fn error_expr(&self) -> Expr {
+        Expr {
+            kind: ExprKind::Nil,
+            span: self.previous_span(),
+        }
+    }
+
+    /// a program is a sequence of declarations
+    /// declaration <- varDecl
+    ///                statement
+    fn declaration(&mut self) -> Option<Stmt> {
+        let stmt = if self.parser.match_token(TokenType::Var) {
+            self.variable_declaration()
+        } else {
+            self.statement()
+        };
+
+        if self.parser.panicking {
+            self.parser.synchronize();
+            return None;
+        }
+        Some(stmt)
+    }
+
+    fn variable_declaration(&mut self) -> Stmt {
+        let start = self.previous_span();
+        self.parser.consume(TokenType::Identifier, "Expected variable name.");
+        let name = self.parser.previous.lexeme.to_owned();
+        let name_span = self.previous_span();
+
+        let initializer = if self.parser.match_token(TokenType::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.parser.consume(TokenType::Semicolon, "Expected ';' after variable declaration");
+
+        Stmt {
+            kind: StmtKind::Var {
+                name,
+                name_span,
+                initializer,
+            },
+            span: start.to(self.previous_span()),
+        }
+    }
+
+    /// statement <- exprStmt
+    ///              printStmt
+    fn statement(&mut self) -> Stmt {
+        if self.parser.match_token(TokenType::Print) {
+            let start = self.previous_span();
+            let expr = self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expected ';' after print statement.");
+            Stmt {
+                kind: StmtKind::Print(expr),
+                span: start.to(self.previous_span()),
+            }
+        } else {
+            let expr = self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expected ';' after expression.");
+            Stmt {
+                span: expr.span.to(self.previous_span()),
+                kind: StmtKind::Expression(expr),
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, prec: Precedence) -> Expr {
+        self.parser.advance();
+        let Some(prefix_rule) = self.get_rule(self.parser.previous.token_type).prefix else {
+            self.parser.error("Expect expression.");
+            return self.error_expr();
+        };
+
+        // only the outermost expression may be the target of an assignment
+        // ex> a[0] = 1 is fine, but 1 + a[0] = 1 is not
+        let can_assign = prec <= Precedence::Assignment;
+        let mut expr = prefix_rule(self, can_assign);
+
+        while prec <= self.get_rule(self.parser.current.token_type).precedence {
+            // we keep parsing if higher (or equal) parsing rules keep coming
+            // ex> 3 + 4 * 2
+            //       ^--
+            self.parser.advance();
+            let infix_rule = self
+                .get_rule(self.parser.previous.token_type)
+                .infix
+                .expect("Infix operators have an infix rule.");
+            expr = infix_rule(self, expr, can_assign);
+        }
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.parser.error("Invalid assignment target.");
+        }
+        expr
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Expr {
+        let token = &self.parser.previous;
+        let value = token.lexeme.parse::<f64>().expect("Expected number.");
+        Expr {
+            kind: ExprKind::Number(value),
+            span: self.previous_span(),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Expr {
+        let lexeme = &self.parser.previous.lexeme;
+        let value = lexeme[1..lexeme.len() - 1].to_owned();
+        Expr {
+            kind: ExprKind::String(value),
+            span: self.previous_span(),
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Expr {
+        let kind = match self.parser.previous.token_type {
+            TokenType::Nil => ExprKind::Nil,
+            TokenType::True => ExprKind::Bool(true),
+            TokenType::False => ExprKind::Bool(false),
+            op_type => panic!("Invalid literal token {:?}", op_type),
+        };
+        Expr {
+            kind,
+            span: self.previous_span(),
+        }
+    }
+
+    fn variable(&mut self, _can_assign: bool) -> Expr {
+        Expr {
+            kind: ExprKind::Variable(self.parser.previous.lexeme.to_owned()),
+            span: self.previous_span(),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Expr {
+        let start = self.previous_span();
+        let inner = self.expression();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after expression.");
+        Expr {
+            kind: ExprKind::Grouping(Box::new(inner)),
+            span: start.to(self.previous_span()),
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Expr {
+        let start = self.previous_span();
+        let op = match self.parser.previous.token_type {
+            TokenType::Minus => UnaryOp::Negate,
+            TokenType::Bang => UnaryOp::Not,
+            TokenType::Tilde => UnaryOp::BitNot,
+            op_type => panic!("Invalid unary operator token {:?}", op_type),
+        };
+
+        // the operand binds tighter than the operator itself,
+        // except for '**' which sits above unary: -2 ** 2 is -(2 ** 2)
+        let operand = self.parse_precedence(Precedence::Unary);
+        Expr {
+            span: start.to(operand.span),
+            kind: ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+        }
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let op_type = self.parser.previous.token_type;
+        let prec = self.get_rule(op_type).precedence;
+
+        // '**' is right associative, so its right operand may contain another '**'
+        // ex> 2 ** 3 ** 2 == 2 ** (3 ** 2)
+        let right = if op_type == TokenType::StarStar {
+            self.parse_precedence(prec)
+        } else {
+            self.parse_precedence(prec.next())
+        };
+
+        Expr {
+            span: left.span.to(right.span),
+            kind: ExprKind::Binary {
+                op: BinaryOp::from_token(op_type),
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        }
+    }
+
+    /// map <- '{' ( expression ':' expression ( ',' expression ':' expression )* ','? )? '}'
+    fn map(&mut self, _can_assign: bool) -> Expr {
+        let start = self.previous_span();
+        let mut entries = Vec::new();
+
+        if !self.parser.check(TokenType::RightBrace) {
+            loop {
+                let key = self.expression();
+                self.parser
+                    .consume(TokenType::Colon, "Expected ':' after map key.");
+                let value = self.expression();
+                entries.push((key, value));
+
+                if !self.parser.match_token(TokenType::Comma)
+                    || self.parser.check(TokenType::RightBrace)
+                {
+                    break;
+                }
+            }
+        }
+
+        self.parser
+            .consume(TokenType::RightBrace, "Expected '}' after map entries.");
+
+        if entries.len() > u8::MAX as usize {
+            self.parser.error("Too many entries in map literal.");
+        }
+        Expr {
+            kind: ExprKind::Map(entries),
+            span: start.to(self.previous_span()),
+        }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> Expr {
+        let mut args = Vec::new();
+
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                args.push(self.expression());
+                if args.len() == u8::MAX as usize + 1 {
+                    self.parser.error("Can't have more than 255 arguments.");
+                }
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.parser
+            .consume(TokenType::RightParen, "Expected ')' after arguments.");
+        Expr {
+            span: callee.span.to(self.previous_span()),
+            kind: ExprKind::Call {
+                callee: Box::new(callee),
+                args,
+            },
+        }
+    }
+
+    fn index(&mut self, target: Expr, can_assign: bool) -> Expr {
+        let index = self.expression();
+        self.parser
+            .consume(TokenType::RightBracket, "Expected ']' after index.");
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            let value = self.expression();
+            Expr {
+                span: target.span.to(value.span),
+                kind: ExprKind::SetIndex {
+                    target: Box::new(target),
+                    index: Box::new(index),
+                    value: Box::new(value),
+                },
+            }
+        } else {
+            Expr {
+                span: target.span.to(self.previous_span()),
+                kind: ExprKind::Index {
+                    target: Box::new(target),
+                    index: Box::new(index),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_expr(source: &str) -> Expr {
+        let program = parse(source).expect("Failed to parse.");
+        match program.statements.into_iter().next().map(|stmt| stmt.kind) {
+            Some(StmtKind::Expression(expr)) => expr,
+            stmt => panic!("Expected an expression statement, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let expr = parse_expr("-2 ** 3 ** 2 + m[1](x);");
+        let ExprKind::Binary { op, left, right } = expr.kind else {
+            panic!("Expected a binary expression.");
+        };
+        assert_eq!(op, BinaryOp::Add);
+        assert!(matches!(right.kind, ExprKind::Call { .. }));
+
+        let ExprKind::Unary { op, operand } = left.kind else {
+            panic!("Expected a unary expression.");
+        };
+        assert_eq!(op, UnaryOp::Negate);
+        let ExprKind::Binary { right, .. } = operand.kind else {
+            panic!("Expected '**' under the negation.");
+        };
+        assert!(matches!(right.kind, ExprKind::Binary { op: BinaryOp::Power, .. }));
+    }
+
+    #[test]
+    fn spans() {
+        let source = "var a = 1;\nprint {\"k\":\n  a + 2}[\"k\"];";
+        let program = parse(source).unwrap();
+        assert_eq!(program.statements.len(), 2);
+        assert_eq!(program.span.end_line, 3);
+
+        let stmt = &program.statements[1];
+        assert_eq!(&source[stmt.span.start..stmt.span.end], "print {\"k\":\n  a + 2}[\"k\"];");
+        assert_eq!((stmt.span.line, stmt.span.end_line), (2, 3));
+
+        let StmtKind::Print(ref expr) = stmt.kind else {
+            panic!("Expected a print statement.");
+        };
+        let ExprKind::Index { ref target, .. } = expr.kind else {
+            panic!("Expected an index expression.");
+        };
+        let ExprKind::Map(ref entries) = target.kind else {
+            panic!("Expected a map literal.");
+        };
+        let value = &entries[0].1;
+        assert_eq!(&source[value.span.start..value.span.end], "a + 2");
+        assert_eq!(value.span.line, 3);
+
+        let StmtKind::Var { ref name_span, .. } = program.statements[0].kind else {
+            panic!("Expected a variable declaration.");
+        };
+        assert_eq!(&source[name_span.start..name_span.end], "a");
+    }
+
+    #[test]
+    fn infix_rules_match_precedences() {
+        // a token parses as an infix operator exactly when it has a precedence
+        for (i, rule) in init_rules().iter().enumerate() {
+            assert_eq!(rule.infix.is_some(), rule.precedence != Precedence::None, "token {}", i);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("print 1 +;").is_none());
+        assert!(parse("1 + m[0] = 2;").is_none());
+        assert!(parse("var a = 1; print {1: a;").is_none());
+        assert!(parse("m[0] = 2;").is_some());
+    }
+}
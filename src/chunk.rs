@@ -1,6 +1,7 @@
 use crate::opcode::Opcode;
 use crate::value::Value;
 
+#[derive(Debug)]
 pub struct Chunk {
     code: Vec<u8>,
     pub lines: Vec<u32>,
@@ -16,6 +17,20 @@ impl Chunk {
         Chunk { code, lines, values }
     }
 
+    /// Assembles a chunk from its parts, without checking them.
+    /// Use `Chunk::verify` before running a chunk built this way.
+    pub fn from_parts(code: Vec<u8>, lines: Vec<u32>, values: Vec<Value>) -> Self {
+        Chunk { code, lines, values }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.values
+    }
+
     pub fn read(&self, addr: usize) -> u8 {
         self.code[addr]
     }
@@ -49,7 +64,25 @@ impl Chunk {
         (self.values.len() - 1).try_into().unwrap()
     }
 
+    /// Drops the code from `len` onwards along with its lines,
+    /// and the constants from index `constants` onwards
+    pub fn truncate(&mut self, len: usize, constants: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.values.truncate(constants);
+    }
+
     pub fn len(&self) -> usize {
         self.code.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,291 @@
+//! Generates bytecode from a syntax tree, as the back end of `Compiler`.
+//!
+//! Each instruction is given the line of the last token of the node it belongs
+//! to, so that an operation split over several lines reports the line its
+//! last operand ends on.
+
+use crate::ast::{BinaryOp, Expr, ExprKind, Program, Stmt, StmtKind, UnaryOp};
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+use crate::value::Value;
+use std::rc::Rc;
+
+pub struct Codegen {
+    chunk: Chunk,
+}
+
+/// the offset and value of an expression that compiled to a single literal
+type Literal = Option<(usize, Value)>;
+
+impl Codegen {
+    pub fn new(chunk: Chunk) -> Self {
+        Codegen { chunk }
+    }
+
+    pub fn generate(mut self, program: &Program) -> Chunk {
+        for stmt in &program.statements {
+            self.statement(stmt);
+        }
+
+        self.chunk.write_opcode(Opcode::Return, program.span.end_line);
+        self.chunk.optimize();
+        self.chunk
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        let line = stmt.span.end_line;
+
+        match stmt.kind {
+            StmtKind::Print(ref expr) => {
+                self.expression(expr);
+                self.chunk.write_opcode(Opcode::Print, line);
+            }
+            StmtKind::Expression(ref expr) => {
+                self.expression(expr);
+                self.chunk.write_opcode(Opcode::Pop, line);
+            }
+            StmtKind::Var {
+                ref name,
+                ref name_span,
+                ref initializer,
+            } => {
+                let global = self.chunk.add_const(Value::Ident(Rc::new(name.to_owned())));
+                match initializer {
+                    Some(expr) => {
+                        self.expression(expr);
+                    }
+                    None => self.chunk.write_opcode(Opcode::Nil, name_span.end_line),
+                }
+                self.chunk.write_two(Opcode::DefineGlobal, global, line);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Literal {
+        let line = expr.span.end_line;
+
+        match expr.kind {
+            ExprKind::Number(n) => self.literal(Value::Number(n), line),
+            ExprKind::String(ref s) => self.literal(Value::String(Rc::new(s.to_owned())), line),
+            ExprKind::Bool(b) => self.literal(Value::Bool(b), line),
+            ExprKind::Nil => self.literal(Value::Nil, line),
+            ExprKind::Variable(ref name) => {
+                let index = self.chunk.add_const(Value::Ident(Rc::new(name.to_owned())));
+                self.chunk.write_two(Opcode::GetGlobal, index, line);
+                None
+            }
+            ExprKind::Grouping(ref inner) => self.expression(inner),
+            ExprKind::Unary { op, ref operand } => {
+                let opcode = match op {
+                    UnaryOp::Negate => Opcode::Negate,
+                    UnaryOp::Not => Opcode::Not,
+                    UnaryOp::BitNot => Opcode::BitNot,
+                };
+
+                if let Some((offset, value)) = self.expression(operand) {
+                    if let Some(value) = fold_unary(opcode, value) {
+                        truncate_literals(&mut self.chunk, offset);
+                        return self.literal(value, line);
+                    }
+                }
+                self.chunk.write_opcode(opcode, line);
+                None
+            }
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => {
+                let opcodes = binary_opcodes(op);
+                let left = self.expression(left);
+                let right = self.expression(right);
+
+                if let (Some((offset, a)), Some((_, b))) = (left, right) {
+                    let folded = fold_binary(opcodes[0], a, b).and_then(|value| {
+                        opcodes[1..]
+                            .iter()
+                            .try_fold(value, |value, &opcode| fold_unary(opcode, value))
+                    });
+                    if let Some(value) = folded {
+                        truncate_literals(&mut self.chunk, offset);
+                        return self.literal(value, line);
+                    }
+                }
+
+                for &opcode in opcodes {
+                    self.chunk.write_opcode(opcode, line);
+                }
+                None
+            }
+            ExprKind::Map(ref entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.chunk.write_two(Opcode::BuildMap, entries.len() as u8, line);
+                None
+            }
+            ExprKind::Index {
+                ref target,
+                ref index,
+            } => {
+                self.expression(target);
+                self.expression(index);
+                self.chunk.write_opcode(Opcode::GetIndex, line);
+                None
+            }
+            ExprKind::SetIndex {
+                ref target,
+                ref index,
+                ref value,
+            } => {
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+                self.chunk.write_opcode(Opcode::SetIndex, line);
+                None
+            }
+            ExprKind::Call {
+                ref callee,
+                ref args,
+            } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.chunk.write_two(Opcode::Call, args.len() as u8, line);
+                None
+            }
+        }
+    }
+
+    /// emits a literal, using the dedicated opcodes for nil and booleans
+    fn literal(&mut self, value: Value, line: u32) -> Literal {
+        let offset = self.chunk.len();
+        match value {
+            Value::Nil => self.chunk.write_opcode(Opcode::Nil, line),
+            Value::Bool(true) => self.chunk.write_opcode(Opcode::True, line),
+            Value::Bool(false) => self.chunk.write_opcode(Opcode::False, line),
+            ref value => {
+                let index = self.chunk.add_const(value.clone());
+                self.chunk.write_two(Opcode::Constant, index, line);
+            }
+        }
+        Some((offset, value))
+    }
+}
+
+/// the instructions the compiler emits for `op`, before peephole optimization
+fn binary_opcodes(op: BinaryOp) -> &'static [Opcode] {
+    match op {
+        BinaryOp::Add => &[Opcode::Add],
+        BinaryOp::Subtract => &[Opcode::Subtract],
+        BinaryOp::Multiply => &[Opcode::Multiply],
+        BinaryOp::Divide => &[Opcode::Divide],
+        BinaryOp::Modulo => &[Opcode::Modulo],
+        BinaryOp::Power => &[Opcode::Power],
+        BinaryOp::FloorDivide => &[Opcode::FloorDivide],
+        BinaryOp::BitAnd => &[Opcode::BitAnd],
+        BinaryOp::BitOr => &[Opcode::BitOr],
+        BinaryOp::BitXor => &[Opcode::BitXor],
+        BinaryOp::ShiftLeft => &[Opcode::ShiftLeft],
+        BinaryOp::ShiftRight => &[Opcode::ShiftRight],
+        BinaryOp::Equal => &[Opcode::Equal],
+        BinaryOp::NotEqual => &[Opcode::Equal, Opcode::Not],
+        BinaryOp::Greater => &[Opcode::Greater],
+        BinaryOp::Lesser => &[Opcode::Lesser],
+        BinaryOp::GreaterEqual => &[Opcode::Lesser, Opcode::Not],
+        BinaryOp::LesserEqual => &[Opcode::Greater, Opcode::Not],
+        BinaryOp::And => &[Opcode::And],
+        BinaryOp::Or => &[Opcode::Or],
+    }
+}
+
+/// Drops the literal instructions from `offset` to the end of the code,
+/// along with their constants. The operands' constants were the last ones
+/// added, and nothing else refers to them.
+fn truncate_literals(chunk: &mut Chunk, offset: usize) {
+    let mut constants = chunk.constants().len();
+    let mut i = offset;
+    while i < chunk.len() {
+        let opcode = chunk.read_opcode(i);
+        if opcode == Opcode::Constant {
+            constants = constants.min(chunk.read(i + 1) as usize);
+        }
+        i += opcode.len();
+    }
+    chunk.truncate(offset, constants);
+}
+
+/// Evaluates a unary operator on a literal at compile time.
+/// Returns `None` where the vm would raise an error, so that it still does at runtime.
+fn fold_unary(opcode: Opcode, operand: Value) -> Option<Value> {
+    match opcode {
+        Opcode::Negate => (-operand).ok(),
+        Opcode::Not => Some(Value::Bool(!operand.truthy())),
+        Opcode::BitNot => operand.bit_not().ok(),
+        _ => None,
+    }
+}
+
+/// Evaluates a binary operator on two literals at compile time, like `fold_unary`
+fn fold_binary(opcode: Opcode, a: Value, b: Value) -> Option<Value> {
+    use std::cmp::Ordering;
+
+    match opcode {
+        Opcode::Add => (a + b).ok(),
+        Opcode::Subtract => (a - b).ok(),
+        Opcode::Multiply => (a * b).ok(),
+        Opcode::Divide => (a / b).ok(),
+        Opcode::Modulo => (a % b).ok(),
+        Opcode::Power => a.pow(b).ok(),
+        Opcode::FloorDivide => a.floor_div(b).ok(),
+        Opcode::BitAnd => (a & b).ok(),
+        Opcode::BitOr => (a | b).ok(),
+        Opcode::BitXor => (a ^ b).ok(),
+        Opcode::ShiftLeft => (a << b).ok(),
+        Opcode::ShiftRight => (a >> b).ok(),
+        Opcode::Equal => Some(Value::Bool(a == b)),
+        Opcode::Greater => a.partial_cmp(&b).map(|o| Value::Bool(o == Ordering::Greater)),
+        Opcode::Lesser => a.partial_cmp(&b).map(|o| Value::Bool(o == Ordering::Less)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+
+    fn generate(source: &str) -> Chunk {
+        let program = ast::parse(source).expect("Failed to parse.");
+        Codegen::new(Chunk::new()).generate(&program)
+    }
+
+    #[test]
+    fn lines() {
+        use Opcode::*;
+
+        let chunk = generate("var a;\nvar b = a !=\n  -a;\nprint\n  b;");
+        let lines: Vec<(Opcode, u32)> = chunk
+            .disassemble()
+            .into_iter()
+            .map(|inst| (inst.opcode, inst.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Nil, 1),
+                (DefineGlobal, 1),
+                (GetGlobal, 2),
+                (GetGlobal, 3),
+                (Negate, 3),
+                (NotEqual, 3),
+                (DefineGlobal, 3),
+                (GetGlobal, 5),
+                (Print, 5),
+                (Return, 5),
+            ]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Line coverage of scripts, gathered from the profile of their runs.
+//!
+//! The lines a script has code on are the ones in `Chunk::lines`, leaving out
+//! the `Return` that ends every chunk on the line the source ends on. A line's
+//! hit count is the number of times its first instruction ran, which is how
+//! many times execution reached the line.
+
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+use crate::profile::Profile;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub path: String,
+    /// hits of every line with code
+    pub lines: BTreeMap<u32, u64>,
+}
+
+impl FileCoverage {
+    pub fn new(path: &str, chunk: &Chunk, profile: &Profile) -> FileCoverage {
+        let mut instructions = chunk.disassemble();
+        if instructions.last().map(|inst| inst.opcode) == Some(Opcode::Return) {
+            instructions.pop();
+        }
+
+        let mut lines = BTreeMap::new();
+        for inst in &instructions {
+            lines
+                .entry(inst.line)
+                .or_insert_with(|| profile.offset_count(inst.offset));
+        }
+        FileCoverage {
+            path: path.to_owned(),
+            lines,
+        }
+    }
+
+    pub fn found(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn hit(&self) -> usize {
+        self.lines.values().filter(|&&hits| hits > 0).count()
+    }
+
+    /// lines with code that never ran
+    pub fn missed(&self) -> impl Iterator<Item = u32> + '_ {
+        self.lines
+            .iter()
+            .filter(|&(_, &hits)| hits == 0)
+            .map(|(&line, _)| line)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    pub files: Vec<FileCoverage>,
+}
+
+impl Coverage {
+    pub fn add(&mut self, file: FileCoverage) {
+        self.files.push(file);
+    }
+
+    /// The coverage as an lcov tracefile, as read by genhtml and most editors
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for file in &self.files {
+            writeln!(out, "TN:").unwrap();
+            writeln!(out, "SF:{}", file.path).unwrap();
+            for (line, hits) in &file.lines {
+                writeln!(out, "DA:{},{}", line, hits).unwrap();
+            }
+            writeln!(out, "LF:{}", file.found()).unwrap();
+            writeln!(out, "LH:{}", file.hit()).unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    /// A table of the lines covered in each file, with the lines missed, and a total
+    pub fn summary(&self) -> String {
+        let percent = |hit: usize, found: usize| match found {
+            0 => 100.0,
+            found => 100.0 * hit as f64 / found as f64,
+        };
+        let row = |out: &mut String, hit: usize, found: usize, name: &str| {
+            let lines = format!("{}/{}", hit, found);
+            write!(out, "{:>7.2}%  {:>9}  {}", percent(hit, found), lines, name).unwrap();
+        };
+
+        let mut out = String::new();
+        let (mut hit, mut found) = (0, 0);
+        for file in &self.files {
+            row(&mut out, file.hit(), file.found(), &file.path);
+            let missed: Vec<String> = file.missed().map(|line| line.to_string()).collect();
+            if !missed.is_empty() {
+                write!(out, "  (missed {})", missed.join(", ")).unwrap();
+            }
+            out.push('\n');
+            hit += file.hit();
+            found += file.found();
+        }
+        row(&mut out, hit, found, "total");
+        out.push('\n');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn coverage(path: &str, source: &str) -> FileCoverage {
+        let run = testing::run(source);
+        FileCoverage::new(path, &run.chunk.unwrap(), &run.profile.unwrap())
+    }
+
+    #[test]
+    fn lines() {
+        let file = coverage(
+            "a.lox",
+            "var a = 1;\n\nprint a +\n  1;\nprint b;\nprint a;\n",
+        );
+        assert_eq!(
+            file.lines.keys().copied().collect::<Vec<_>>(),
+            vec![1, 3, 4, 5, 6]
+        );
+        assert_eq!((file.hit(), file.found()), (4, 5));
+        assert_eq!(file.missed().collect::<Vec<_>>(), vec![6]);
+
+        let mut coverage = Coverage::default();
+        coverage.add(file);
+        coverage.add(self::coverage("b.lox", "print 1;"));
+
+        let lcov = coverage.lcov();
+        assert!(lcov.starts_with("TN:\nSF:a.lox\nDA:1,1\nDA:3,1\nDA:4,1\n"));
+        assert!(lcov.contains("DA:6,0\nLF:5\nLH:4\nend_of_record\nTN:\nSF:b.lox\n"));
+        assert_eq!(
+            coverage.summary(),
+            "  80.00%        4/5  a.lox  (missed 6)\n\
+             \x20100.00%        1/1  b.lox\n\
+             \x20 83.33%        5/6  total\n"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! An interactive source-level debugger, behind `rustox debug`.
+//!
+//! The debugger is a `DebugHook`, deciding before every instruction whether
+//! to pause and read commands. It pauses on the first instruction of a line,
+//! found through `Chunk::lines`, when that line has a breakpoint or when stepping.
+//!
+//! Lox has no functions of its own yet, so the script is the only frame:
+//! `next` steps like `step`, and `finish` runs until the script returns.
+
+use crate::chunk::Chunk;
+use crate::value::{StackSlot, Value};
+use crate::vm::{DebugAction, DebugHook, Vm};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+const HELP: &str = "\
+break [line]    set a breakpoint on a line, or list them (b)
+delete <line>   remove the breakpoint on a line
+step            run to the next line (s)
+next            run to the next line in this frame (n)
+stepi           run one instruction (si)
+finish          run until the current frame returns
+continue        run until a breakpoint (c)
+stack           show the value stack
+globals         show the globals defined by the script
+print <expr>    evaluate an expression (p)
+list            show the source around the current line (l)
+quit            stop the script (q)
+An empty line repeats the last command.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Step,
+    StepInstruction,
+    Continue,
+    /// the commands ran out, so the script runs to the end
+    Detached,
+}
+
+pub struct Debugger<R, W> {
+    source: Vec<String>,
+    commands: R,
+    out: W,
+    /// lines to pause on
+    breakpoints: BTreeSet<u32>,
+    resume: Resume,
+    /// the line of the previous instruction
+    last_line: Option<u32>,
+    last_command: String,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    /// Creates a debugger for a script compiled from `source`, reading commands
+    /// from `commands` and writing to `out`. It pauses before the first line.
+    pub fn new(source: &str, commands: R, out: W) -> Self {
+        Debugger {
+            source: source.lines().map(str::to_owned).collect(),
+            commands,
+            out,
+            breakpoints: BTreeSet::new(),
+            resume: Resume::Step,
+            last_line: None,
+            last_command: String::new(),
+        }
+    }
+
+    fn show_line(&mut self, line: u32) {
+        let text = source_line(&self.source, line);
+        let _ = writeln!(self.out, "{:>4}  {}", line, text);
+    }
+
+    /// reads and runs commands until one resumes the script
+    fn pause(&mut self, vm: &mut Vm, chunk: &Chunk, line: u32) -> DebugAction {
+        loop {
+            let _ = write!(self.out, "(lox) ");
+            let _ = self.out.flush();
+
+            let mut command = String::new();
+            if !matches!(self.commands.read_line(&mut command), Ok(n) if n > 0) {
+                let _ = writeln!(self.out);
+                self.resume = Resume::Detached;
+                return DebugAction::Continue;
+            }
+
+            let mut command = command.trim().to_owned();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            } else {
+                self.last_command = command.clone();
+            }
+            let (name, argument) = match command.split_once(' ') {
+                Some((name, argument)) => (name, argument.trim()),
+                None => (command.as_str(), ""),
+            };
+
+            let resume = match name {
+                "" => None,
+                "s" | "step" | "n" | "next" => Some(Resume::Step),
+                "si" | "stepi" => Some(Resume::StepInstruction),
+                "c" | "continue" | "finish" => Some(Resume::Continue),
+                "q" | "quit" => return DebugAction::Stop,
+                "b" | "break" => {
+                    self.set_breakpoint(chunk, argument);
+                    None
+                }
+                "delete" => {
+                    self.delete_breakpoint(argument);
+                    None
+                }
+                "stack" => {
+                    self.show_stack(vm);
+                    None
+                }
+                "globals" => {
+                    self.show_globals(vm);
+                    None
+                }
+                "p" | "print" => {
+                    let _ = match vm.evaluate(argument) {
+                        Ok(value) => writeln!(self.out, "{}", value),
+                        Err(error) => writeln!(self.out, "{}", error),
+                    };
+                    None
+                }
+                "l" | "list" => {
+                    self.list(line);
+                    None
+                }
+                "help" => {
+                    let _ = writeln!(self.out, "{}", HELP);
+                    None
+                }
+                _ => {
+                    let _ = writeln!(self.out, "Unknown command '{}'. Try 'help'.", name);
+                    None
+                }
+            };
+
+            if let Some(resume) = resume {
+                self.resume = resume;
+                return DebugAction::Continue;
+            }
+        }
+    }
+
+    /// sets a breakpoint on the first line at or after the one given that has code
+    fn set_breakpoint(&mut self, chunk: &Chunk, argument: &str) {
+        if argument.is_empty() {
+            let lines: Vec<String> = self.breakpoints.iter().map(u32::to_string).collect();
+            let _ = match lines.is_empty() {
+                true => writeln!(self.out, "No breakpoints."),
+                false => writeln!(self.out, "Breakpoints on lines {}.", lines.join(", ")),
+            };
+            return;
+        }
+
+        let Ok(wanted) = argument.parse::<u32>() else {
+            let _ = writeln!(self.out, "Expected a line number.");
+            return;
+        };
+        match chunk.lines.iter().filter(|&&line| line >= wanted).min() {
+            Some(&line) => {
+                self.breakpoints.insert(line);
+                let _ = writeln!(self.out, "Breakpoint on line {}.", line);
+            }
+            None => {
+                let _ = writeln!(self.out, "No code on or after line {}.", wanted);
+            }
+        }
+    }
+
+    fn delete_breakpoint(&mut self, argument: &str) {
+        let _ = match argument.parse::<u32>() {
+            Ok(line) if self.breakpoints.remove(&line) => {
+                writeln!(self.out, "Deleted the breakpoint on line {}.", line)
+            }
+            Ok(line) => writeln!(self.out, "No breakpoint on line {}.", line),
+            Err(_) => writeln!(self.out, "Expected a line number."),
+        };
+    }
+
+    fn show_stack(&mut self, vm: &Vm) {
+        if vm.stack.is_empty() {
+            let _ = writeln!(self.out, "The stack is empty.");
+            return;
+        }
+        let mut line = String::new();
+        for slot in &vm.stack {
+            line.push_str(&format!("[ {} ]", slot.to_value()));
+        }
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    fn show_globals(&mut self, vm: &Vm) {
+        // built-in functions are left out
+        let mut globals: Vec<(&str, &Value)> = vm
+            .globals()
+            .filter(|(_, value)| !matches!(value, Value::Native(_)))
+            .collect();
+        if globals.is_empty() {
+            let _ = writeln!(self.out, "No globals are defined.");
+            return;
+        }
+
+        globals.sort_by_key(|&(name, _)| name);
+        for (name, value) in globals {
+            let _ = writeln!(self.out, "{} = {}", name, value);
+        }
+    }
+
+    fn list(&mut self, line: u32) {
+        let first = line.saturating_sub(2).max(1);
+        let last = line.saturating_add(2).min(self.source.len() as u32);
+        for n in first..=last {
+            let marker = if n == line { '>' } else { ' ' };
+            let text = source_line(&self.source, n);
+            let _ = writeln!(self.out, "{} {:>4}  {}", marker, n, text);
+        }
+    }
+}
+
+/// the text of a 1-based line, which is empty for line 0 and past the end of the source
+fn source_line(source: &[String], line: u32) -> &str {
+    (line as usize)
+        .checked_sub(1)
+        .and_then(|i| source.get(i))
+        .map_or("", |text| text.as_str())
+}
+
+impl<R: BufRead, W: Write> DebugHook for Debugger<R, W> {
+    fn before_instruction(&mut self, vm: &mut Vm, chunk: &Chunk, offset: usize) -> DebugAction {
+        let line = chunk.lines[offset];
+        let new_line = self.last_line != Some(line);
+        self.last_line = Some(line);
+
+        let pause = match self.resume {
+            Resume::StepInstruction => true,
+            Resume::Step => new_line,
+            Resume::Continue => new_line && self.breakpoints.contains(&line),
+            Resume::Detached => false,
+        };
+        if !pause {
+            return DebugAction::Continue;
+        }
+
+        if self.resume == Resume::Continue {
+            let _ = writeln!(self.out, "Breakpoint on line {}.", line);
+        }
+        if self.resume == Resume::StepInstruction {
+            let _ = writeln!(self.out, "{}", chunk.decode(offset));
+        } else {
+            self.show_line(line);
+        }
+        self.pause(vm, chunk, line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::vm::InterpretResult;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// runs `source` under the debugger with `commands`, returning the
+    /// session and the printed output
+    fn debug(source: &str, commands: &str) -> (InterpretResult, String) {
+        let mut compiler = Compiler::new(source);
+        assert!(!compiler.compile(Chunk::new()));
+
+        let out = SharedBuf::default();
+        let mut vm = Vm::new();
+        vm.set_output(out.clone());
+        vm.set_error_fn(|_| ());
+        let commands = std::io::Cursor::new(commands.to_owned());
+        vm.set_debug_hook(Debugger::new(source, commands, out.clone()));
+        let result = vm.interpret(compiler.take_chunk());
+
+        let session = String::from_utf8(out.0.borrow().clone()).unwrap();
+        (result, session)
+    }
+
+    const SCRIPT: &str = "var a = 1;\nvar m = {};\n\nm[\"k\"] = a + 1;\nprint m[\"k\"];\n";
+
+    #[test]
+    fn breakpoints() {
+        let (result, session) = debug(SCRIPT, "break 3\nb\nc\nglobals\ndelete 4\ndelete 4\nc\n");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            session,
+            "   1  var a = 1;\n\
+             (lox) Breakpoint on line 4.\n\
+             (lox) Breakpoints on lines 4.\n\
+             (lox) Breakpoint on line 4.\n   4  m[\"k\"] = a + 1;\n\
+             (lox) a = 1\nm = {}\n\
+             (lox) Deleted the breakpoint on line 4.\n\
+             (lox) No breakpoint on line 4.\n\
+             (lox) 2\n"
+        );
+    }
+
+    #[test]
+    fn stepping() {
+        let (result, session) = debug(SCRIPT, "s\n\nsi\nstack\nsi\nstack\nl\nq\n");
+        // quitting is not an error, and the rest of the script does not run
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            session,
+            "   1  var a = 1;\n\
+             (lox)    2  var m = {};\n\
+             (lox)    4  m[\"k\"] = a + 1;\n\
+             (lox) 0010    | Constant         4    '\"k\"'\n\
+             (lox) [ {} ]\n\
+             (lox) 0012    | GetGlobal        5    '@a'\n\
+             (lox) [ {} ][ \"k\" ]\n\
+             (lox)      2  var m = {};\n     3  \n\
+             >    4  m[\"k\"] = a + 1;\n     5  print m[\"k\"];\n\
+             (lox) "
+        );
+    }
+
+    #[test]
+    fn evaluation() {
+        let (_, session) = debug(
+            SCRIPT,
+            "b 5\nc\np m[\"k\"] * 10\nprint m[\"k\"] = 7\np nothing\np 1 +\nfinish\n",
+        );
+        assert!(session.ends_with(
+            "(lox) 20\n\
+             (lox) 7\n\
+             (lox) Undefined variable 'nothing'.\n\
+             (lox) [line 1] Error at ';': Expect expression.\n\
+             (lox) 7\n"
+        ));
+    }
+
+    #[test]
+    fn lines_outside_the_source() {
+        let source = vec!["print 1;".to_owned()];
+        assert_eq!(source_line(&source, 0), "");
+        assert_eq!(source_line(&source, 1), "print 1;");
+        assert_eq!(source_line(&source, u32::MAX), "");
+    }
+
+    #[test]
+    fn detaches_at_end_of_commands() {
+        let (result, session) = debug(SCRIPT, "");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(session, "   1  var a = 1;\n(lox) \n2\n");
+    }
+}
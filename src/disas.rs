@@ -1,50 +1,224 @@
 use crate::chunk::Chunk;
 use crate::opcode::Opcode;
+use crate::value::Value;
 use crate::vm::Vm;
+use std::fmt::{self, Display, Write};
 
-impl Chunk {
-    pub fn disas(&self, name: &str) {
-        println!("===== {} =====", name);
+/// A decoded instruction of a chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub line: u32,
+    /// whether the previous byte of code is on the same source line
+    pub same_line: bool,
+    /// `Opcode::Invalid` if the byte at `offset` is not a valid opcode
+    pub opcode: Opcode,
+    /// the operand bytes, or the raw byte of an invalid opcode.
+    /// may be shorter than expected if the code ends early
+    pub operands: Vec<u8>,
+    /// the constant an operand refers to, for instructions that take one
+    pub constant: Option<Value>,
+}
+
+impl Instruction {
+    /// the offset of the following instruction
+    pub fn next(&self) -> usize {
+        match self.opcode {
+            Opcode::Invalid => self.offset + 1,
+            _ => self.offset + 1 + self.operands.len(),
+        }
+    }
+
+    /// writes the opcode and its operands, without the offset and line columns
+    pub fn write_body(&self, out: &mut impl Write) -> fmt::Result {
+        let name = format!("{:?}", self.opcode);
+        match (self.operands.first(), &self.constant) {
+            (Some(operand), Some(constant)) => {
+                write!(out, "{:<16} {:<4} '{}'", name, operand, constant)
+            }
+            (Some(operand), None) => write!(out, "{:<16} {}", name, operand),
+            (None, _) => write!(out, "{}", name),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0>4} ", self.offset)?;
+
+        if self.same_line {
+            write!(f, "{:>4} ", "|")?;
+        } else {
+            write!(f, "{:>4} ", self.line)?;
+        }
+
+        self.write_body(f)
+    }
+}
 
+/// A titled listing of a whole chunk, as printed by `Chunk::disas`
+pub struct Listing<'a> {
+    chunk: &'a Chunk,
+    name: &'a str,
+}
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "===== {} =====", self.name)?;
+        for inst in self.chunk.disassemble() {
+            writeln!(f, "{}", inst)?;
+        }
+        Ok(())
+    }
+}
+
+impl Chunk {
+    pub fn disassemble(&self) -> Vec<Instruction> {
+        let mut insts = Vec::new();
         let mut offset = 0;
         while offset < self.len() {
-            offset = self.disas_inst(offset);
+            let inst = self.decode(offset);
+            offset = inst.next();
+            insts.push(inst);
         }
+        insts
     }
 
-    pub fn disas_inst(&self, offset: usize) -> usize {
-        print!("{addr:0>width$} ", addr=offset, width=4);
+    /// Decodes the instruction at `offset`.
+    /// Never panics on malformed code, so that unverified chunks can be inspected.
+    pub fn decode(&self, offset: usize) -> Instruction {
+        let byte = self.read(offset);
+        let line = self.lines.get(offset).copied().unwrap_or(0);
+        let same_line = offset > 0 && self.lines.get(offset - 1) == Some(&line);
 
-        if offset > 0 && self.lines[offset - 1] == self.lines[offset] {
-            print!("{:>4} ", "|");
-        } else {
-            print!("{:>4} ", self.lines[offset]);
+        let (opcode, operands) = match Opcode::decode(byte) {
+            Some(opcode) => {
+                let end = (offset + opcode.len()).min(self.len());
+                (opcode, self.code()[offset + 1..end].to_vec())
+            }
+            None => (Opcode::Invalid, vec![byte]),
+        };
+
+        let constant = match opcode {
+            Opcode::Constant | Opcode::DefineGlobal | Opcode::GetGlobal => operands
+                .first()
+                .and_then(|&index| self.constants().get(index as usize))
+                .cloned(),
+            _ => None,
+        };
+
+        Instruction {
+            offset,
+            line,
+            same_line,
+            opcode,
+            operands,
+            constant,
         }
+    }
 
-        let opcode = self.read_opcode(offset);
-        self.disas_opcode(opcode, offset);
-        offset + opcode.len()
+    pub fn listing<'a>(&'a self, name: &'a str) -> Listing<'a> {
+        Listing { chunk: self, name }
+    }
+
+    pub fn write_disas(&self, name: &str, out: &mut impl Write) -> fmt::Result {
+        write!(out, "{}", self.listing(name))
+    }
+
+    pub fn disas(&self, name: &str) {
+        print!("{}", self.listing(name));
+    }
+
+    pub fn disas_inst(&self, offset: usize) -> usize {
+        let inst = self.decode(offset);
+        println!("{}", inst);
+        inst.next()
     }
 
     pub fn disas_opcode(&self, opcode: Opcode, offset: usize) {
-        use Opcode::*;
-        match opcode {
-            Invalid | Return | Negate |
-            Add | Subtract | Multiply | Divide |
-            Nil | True | False |
-            Not | Equal | Greater | Lesser |
-            And | Or | Print | Pop => println!("{:?}", opcode),
-            Constant | DefineGlobal | GetGlobal => {
-                let voff = self.read(offset + 1);
-                let constant = self.read_value(voff);
-                println!("{:<16} {:<4} '{}'", format!("{:?}", opcode), voff, constant);
-            },
-        }
+        let inst = self.decode(offset);
+        debug_assert_eq!(inst.opcode, opcode);
+
+        let mut out = String::new();
+        inst.write_body(&mut out).expect("Writing to a String cannot fail.");
+        println!("{}", out);
     }
 }
 
 impl Vm {
+    pub fn write_stack(&self, out: &mut impl Write) -> fmt::Result {
+        write!(out, "{:?}", self.stack)
+    }
+
     pub fn disas_stack(&self) {
-        println!("{:?}", self.stack);
+        let mut out = String::new();
+        self.write_stack(&mut out).expect("Writing to a String cannot fail.");
+        println!("{}", out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let chunk = Chunk::assemble(
+            ".constants
+                @a
+            .code
+                .line 1
+                Nil
+                DefineGlobal 0
+                .line 2
+                BuildMap 0
+                Return",
+        )
+        .unwrap();
+
+        let insts = chunk.disassemble();
+        assert_eq!(insts.len(), 4);
+        assert_eq!(insts[1].opcode, Opcode::DefineGlobal);
+        assert_eq!(insts[1].operands, vec![0]);
+        assert!(insts[1].same_line);
+        assert!(matches!(insts[1].constant, Some(Value::Ident(_))));
+        assert_eq!(insts[2].line, 2);
+        assert!(!insts[2].same_line);
+        assert_eq!(insts[2].constant, None);
+    }
+
+    #[test]
+    fn render() {
+        let chunk = Chunk::assemble(
+            ".constants
+                \"hi\"
+            .code
+                .line 7
+                Constant 0
+                Print
+                Return",
+        )
+        .unwrap();
+
+        let mut out = String::new();
+        chunk.write_disas("test", &mut out).unwrap();
+        assert_eq!(
+            out,
+            "===== test =====\n\
+             0000    7 Constant         0    '\"hi\"'\n\
+             0002    | Print\n\
+             0003    | Return\n"
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let chunk = Chunk::from_parts(vec![200, 1], vec![1, 1], Vec::new());
+        let insts = chunk.disassemble();
+        assert_eq!(insts[0].opcode, Opcode::Invalid);
+        assert_eq!(insts[0].operands, vec![200]);
+        // a constant instruction cut off before its operand
+        assert_eq!(insts[1].operands, Vec::<u8>::new());
+        assert_eq!(insts[1].next(), 2);
     }
 }
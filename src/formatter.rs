@@ -0,0 +1,299 @@
+//! A pretty printer for Lox source, behind `rustox fmt`.
+//!
+//! Statements are printed from the syntax tree, one per line, with canonical
+//! spacing around operators. Comments come from a second scan of the source
+//! with `Scanner::with_comments`, and are placed by their offsets:
+//!
+//! - a comment on the line a statement ends is kept at the end of that line
+//! - any other comment is printed on its own line, before the statement it
+//!   precedes or is inside of
+//!
+//! A map literal written over several lines is printed with one entry per
+//! line, indented by `FormatOptions::indent` for each map it is nested in.
+//! Any other expression is printed on one line.
+//!
+//! A single blank line between statements is kept, and longer runs are collapsed.
+//! Formatting already formatted source leaves it unchanged.
+
+use crate::ast::{self, Expr, ExprKind, Stmt, StmtKind};
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// spaces per level of indentation, inside map literals written over several lines
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { indent: 2 }
+    }
+}
+
+/// Formats a program. Returns `None` if it does not parse,
+/// after reporting the errors to stderr like the compiler.
+pub fn format(source: &str, options: &FormatOptions) -> Option<String> {
+    let program = ast::parse(source)?;
+
+    let mut printer = Printer {
+        source,
+        comments: comments(source),
+        next_comment: 0,
+        options,
+        last_line: None,
+        out: String::new(),
+    };
+    printer.statements(&program.statements, source.len());
+    Some(printer.out)
+}
+
+fn comments(source: &str) -> Vec<Token> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut comments = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Eof => return comments,
+            TokenType::Comment => comments.push(token),
+            _ => (),
+        }
+    }
+}
+
+struct Printer<'a> {
+    source: &'a str,
+    /// every comment in the source, in order
+    comments: Vec<Token>,
+    /// the first comment not printed yet
+    next_comment: usize,
+    options: &'a FormatOptions,
+    /// the source line of what was printed last, if anything
+    last_line: Option<u32>,
+    out: String,
+}
+
+impl Printer<'_> {
+    /// prints statements followed by the comments before `end`
+    fn statements(&mut self, statements: &[Stmt], end: usize) {
+        for (i, stmt) in statements.iter().enumerate() {
+            // comments inside a statement are moved in front of it
+            self.comments_before(stmt.span.end);
+
+            self.start_line(stmt.span.line);
+            self.statement(stmt);
+            self.last_line = Some(stmt.span.end_line);
+
+            let next = statements.get(i + 1).map_or(end, |next| next.span.start);
+            if let Some(comment) = self.comments.get(self.next_comment) {
+                if comment.line == stmt.span.end_line && comment.start < next {
+                    self.out.push(' ');
+                    self.out.push_str(comment.lexeme.trim_end());
+                    self.next_comment += 1;
+                }
+            }
+            self.out.push('\n');
+        }
+
+        self.comments_before(end);
+    }
+
+    /// prints the comments that start before the byte offset `end`, each on its own line
+    fn comments_before(&mut self, end: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start >= end {
+                break;
+            }
+
+            let (line, text) = (comment.line, comment.lexeme.trim_end().to_owned());
+            self.start_line(line);
+            self.out.push_str(&text);
+            self.out.push('\n');
+            self.last_line = Some(line);
+            self.next_comment += 1;
+        }
+    }
+
+    /// starts a new line for something from source line `line`,
+    /// after a blank line if there was one in the source
+    fn start_line(&mut self, line: u32) {
+        if let Some(last) = self.last_line {
+            if line > last + 1 {
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt.kind {
+            StmtKind::Print(ref expr) => {
+                let expr = self.expression(expr, 0);
+                self.out.push_str(&format!("print {};", expr));
+            }
+            StmtKind::Expression(ref expr) => {
+                let expr = self.expression(expr, 0);
+                self.out.push_str(&format!("{};", expr));
+            }
+            StmtKind::Var {
+                ref name,
+                ref initializer,
+                ..
+            } => match initializer {
+                Some(expr) => {
+                    let expr = self.expression(expr, 0);
+                    self.out.push_str(&format!("var {} = {};", name, expr));
+                }
+                None => self.out.push_str(&format!("var {};", name)),
+            },
+        }
+    }
+
+    /// `depth` is the number of multi-line maps the expression is inside of
+    fn expression(&self, expr: &Expr, depth: usize) -> String {
+        match expr.kind {
+            // numbers are kept as written, so that 1.50 does not turn into 1.5
+            ExprKind::Number(_) => self.source[expr.span.start..expr.span.end].to_owned(),
+            ExprKind::String(ref s) => format!("\"{}\"", s),
+            ExprKind::Bool(b) => b.to_string(),
+            ExprKind::Nil => "nil".to_owned(),
+            ExprKind::Variable(ref name) => name.to_owned(),
+            ExprKind::Grouping(ref inner) => format!("({})", self.expression(inner, depth)),
+            ExprKind::Unary { op, ref operand } => {
+                format!("{}{}", op, self.expression(operand, depth))
+            }
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => format!(
+                "{} {} {}",
+                self.expression(left, depth),
+                op,
+                self.expression(right, depth)
+            ),
+            ExprKind::Map(ref entries) => {
+                if entries.is_empty() {
+                    return "{}".to_owned();
+                }
+                if expr.span.line == expr.span.end_line {
+                    let entries: Vec<String> = entries
+                        .iter()
+                        .map(|(key, value)| {
+                            format!(
+                                "{}: {}",
+                                self.expression(key, depth),
+                                self.expression(value, depth)
+                            )
+                        })
+                        .collect();
+                    return format!("{{{}}}", entries.join(", "));
+                }
+
+                let indent = " ".repeat((depth + 1) * self.options.indent);
+                let mut out = String::from("{\n");
+                for (key, value) in entries {
+                    out.push_str(&format!(
+                        "{}{}: {},\n",
+                        indent,
+                        self.expression(key, depth + 1),
+                        self.expression(value, depth + 1)
+                    ));
+                }
+                out.push_str(&" ".repeat(depth * self.options.indent));
+                out.push('}');
+                out
+            }
+            ExprKind::Index {
+                ref target,
+                ref index,
+            } => format!(
+                "{}[{}]",
+                self.expression(target, depth),
+                self.expression(index, depth)
+            ),
+            ExprKind::SetIndex {
+                ref target,
+                ref index,
+                ref value,
+            } => format!(
+                "{}[{}] = {}",
+                self.expression(target, depth),
+                self.expression(index, depth),
+                self.expression(value, depth)
+            ),
+            ExprKind::Call {
+                ref callee,
+                ref args,
+            } => {
+                let args: Vec<String> =
+                    args.iter().map(|arg| self.expression(arg, depth)).collect();
+                format!("{}({})", self.expression(callee, depth), args.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(source: &str) -> String {
+        format(source, &FormatOptions::default()).expect("Failed to parse.")
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(
+            fmt("var   a=1+2 *-3;print a;print{1:2,\"k\" :nil}[1];{} ;\nm [ 0 ]=f( 1,2 )  ;"),
+            "var a = 1 + 2 * -3;\n\
+             print a;\n\
+             print {1: 2, \"k\": nil}[1];\n\
+             {};\n\
+             m[0] = f(1, 2);\n"
+        );
+        assert_eq!(fmt("print (1.50 + 2) ** 2;"), "print (1.50 + 2) ** 2;\n");
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let source = "// header\n\n\n\
+                      var a = 1; // one\n\
+                      print a +   // inside\n  2;\n\
+                      var m = {   // open\n\n\
+                      1: a};\n\
+                      var b;print {b: 1};// at the end\n\
+                      // footer   \n";
+        assert_eq!(
+            fmt(source),
+            "// header\n\n\
+             var a = 1; // one\n\
+             // inside\n\
+             print a + 2;\n\
+             // open\n\
+             var m = {\n  1: a,\n};\n\
+             var b;\n\
+             print {b: 1}; // at the end\n\
+             // footer\n"
+        );
+    }
+
+    #[test]
+    fn indent() {
+        let options = FormatOptions { indent: 4 };
+        assert_eq!(
+            format("print {1: {2: 3,\n4: 5}, 6: {7: 8}};", &options).unwrap(),
+            "print {\n    1: {\n        2: 3,\n        4: 5,\n    },\n    6: {7: 8},\n};\n"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let source = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            if let Some(once) = format(&source, &FormatOptions::default()) {
+                assert_eq!(fmt(&once), once);
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Syntax highlighting for Lox snippets, as HTML or ANSI terminal output.
+//!
+//! Tokens come from `Scanner::with_comments` and are classified by their
+//! `TokenType`. The text between tokens, and any text the scanner rejected,
+//! is copied through unstyled, so the output always reads as the original source.
+
+use crate::scanner::Scanner;
+use crate::token::TokenType;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+    Keyword,
+    /// numbers, strings, `true`, `false` and `nil`
+    Literal,
+    Operator,
+    Comment,
+    Identifier,
+    /// brackets, separators and `;`
+    Punctuation,
+}
+
+impl TokenClass {
+    /// the class of a token, or `None` for errors and the end of input
+    pub fn of(token_type: TokenType) -> Option<TokenClass> {
+        use TokenType::*;
+
+        let class = match token_type {
+            And | Assert | Class | Else | For | Fun | If | Or | Print | Return | Super | This
+            | Var | While => TokenClass::Keyword,
+            Number | String | True | False | Nil => TokenClass::Literal,
+            Minus | Plus | Percent | Slash | Star | Ampersand | Pipe | Caret | Tilde | Bang
+            | BangEqual | Equal | EqualEqual | Greater | GreaterEqual | Lesser | LesserEqual
+            | StarStar | TildeSlash | LesserLesser | GreaterGreater => TokenClass::Operator,
+            Comment => TokenClass::Comment,
+            Identifier => TokenClass::Identifier,
+            LeftParen | RightParen | LeftBrace | RightBrace | LeftBracket | RightBracket
+            | Colon | Comma | Dot | Semicolon => TokenClass::Punctuation,
+            Error | Eof => return None,
+        };
+        Some(class)
+    }
+
+    /// the name used for the class in HTML, as in `lox-keyword`
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenClass::Keyword => "keyword",
+            TokenClass::Literal => "literal",
+            TokenClass::Operator => "operator",
+            TokenClass::Comment => "comment",
+            TokenClass::Identifier => "identifier",
+            TokenClass::Punctuation => "punctuation",
+        }
+    }
+
+    /// the SGR parameters for the class in a terminal, if it is colored
+    fn ansi(&self) -> Option<&'static str> {
+        match self {
+            TokenClass::Keyword => Some("1;35"),
+            TokenClass::Literal => Some("32"),
+            TokenClass::Operator => Some("36"),
+            TokenClass::Comment => Some("90"),
+            TokenClass::Identifier => Some("34"),
+            TokenClass::Punctuation => None,
+        }
+    }
+}
+
+/// The classified tokens of `source`, as byte ranges in order
+pub fn classify(source: &str) -> Vec<(TokenClass, Range<usize>)> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Eof => return tokens,
+            token_type => {
+                if let Some(class) = TokenClass::of(token_type) {
+                    tokens.push((class, token.start..token.start + token.lexeme.len()));
+                }
+            }
+        }
+    }
+}
+
+/// Highlights `source` as a `<pre>` block, with a `lox-<class>` span
+/// around every token except punctuation
+pub fn html(source: &str) -> String {
+    let mut out = String::from("<pre class=\"lox\"><code>");
+    render(source, &mut out, |out, class, text| match class {
+        Some(class) if class != TokenClass::Punctuation => {
+            out.push_str(&format!("<span class=\"lox-{}\">", class.name()));
+            escape_html(out, text);
+            out.push_str("</span>");
+        }
+        _ => escape_html(out, text),
+    });
+    out.push_str("</code></pre>");
+    out
+}
+
+/// Highlights `source` with ANSI escape codes, resetting after every colored token
+pub fn ansi(source: &str) -> String {
+    let mut out = String::new();
+    render(source, &mut out, |out, class, text| {
+        match class.and_then(|class| class.ansi()) {
+            Some(sgr) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", sgr, text)),
+            None => out.push_str(text),
+        }
+    });
+    out
+}
+
+/// calls `write` on every piece of the source in order, with `None` for the text between tokens
+fn render(
+    source: &str,
+    out: &mut String,
+    mut write: impl FnMut(&mut String, Option<TokenClass>, &str),
+) {
+    let mut end = 0;
+    for (class, range) in classify(source) {
+        if range.start > end {
+            write(out, None, &source[end..range.start]);
+        }
+        write(out, Some(class), &source[range.clone()]);
+        end = range.end;
+    }
+    if end < source.len() {
+        write(out, None, &source[end..]);
+    }
+}
+
+fn escape_html(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let source = "var x = nil; // c\nprint x[\"k\"] << 2;";
+        let classes: Vec<(TokenClass, &str)> = classify(source)
+            .into_iter()
+            .map(|(class, range)| (class, &source[range]))
+            .collect();
+
+        use TokenClass::*;
+        assert_eq!(
+            classes,
+            vec![
+                (Keyword, "var"),
+                (Identifier, "x"),
+                (Operator, "="),
+                (Literal, "nil"),
+                (Punctuation, ";"),
+                (Comment, "// c"),
+                (Keyword, "print"),
+                (Identifier, "x"),
+                (Punctuation, "["),
+                (Literal, "\"k\""),
+                (Punctuation, "]"),
+                (Operator, "<<"),
+                (Literal, "2"),
+                (Punctuation, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn html_output() {
+        assert_eq!(
+            html("print a<b; // <&>\n"),
+            "<pre class=\"lox\"><code>\
+             <span class=\"lox-keyword\">print</span> \
+             <span class=\"lox-identifier\">a</span>\
+             <span class=\"lox-operator\">&lt;</span>\
+             <span class=\"lox-identifier\">b</span>; \
+             <span class=\"lox-comment\">// &lt;&amp;&gt;</span>\n\
+             </code></pre>"
+        );
+    }
+
+    #[test]
+    fn ansi_output() {
+        assert_eq!(
+            ansi("print \"é\";"),
+            "\x1b[1;35mprint\x1b[0m \x1b[32m\"é\"\x1b[0m;"
+        );
+    }
+
+    #[test]
+    fn keeps_all_text() {
+        // `$` and the unterminated string are scanner errors
+        let source = "var  a = 1 $ 2;\n\n  print \"open";
+        let strip = |s: &str| {
+            let mut plain = String::new();
+            let mut in_escape = false;
+            for c in s.chars() {
+                match c {
+                    '\x1b' => in_escape = true,
+                    'm' if in_escape => in_escape = false,
+                    _ if in_escape => (),
+                    c => plain.push(c),
+                }
+            }
+            plain
+        };
+        assert_eq!(strip(&ansi(source)), source);
+    }
+}
@@ -1,9 +1,27 @@
+pub mod assembler;
+pub mod ast;
 pub mod chunk;
+pub mod codegen;
 pub mod compiler;
+pub mod coverage;
+pub mod debugger;
 pub mod disas;
+pub mod formatter;
+pub mod highlight;
+pub mod json;
+pub mod lint;
+pub mod lsp;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
 pub mod opcode;
 pub mod parser;
+pub mod peephole;
+pub mod prelude;
+pub mod profile;
 pub mod scanner;
+pub mod serialize;
+pub mod testing;
 pub mod token;
 pub mod value;
+pub mod verifier;
 pub mod vm;
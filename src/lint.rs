@@ -0,0 +1,360 @@
+//! Static checks for common mistakes, behind `rustox lint`.
+//!
+//! | lint                | reports                                                      |
+//! |---------------------|--------------------------------------------------------------|
+//! | `undefined-global`  | a global read before any `var` defines it                    |
+//! | `string-comparison` | a comparison with a string literal that can never succeed    |
+//! | `shadowed-variable` | a `var` that redefines a global or a built-in function       |
+//!
+//! A lint is suppressed for one line with a comment, either at the end of that
+//! line or on its own on the line above:
+//!
+//! ```text
+//! // lint: allow(undefined-global, shadowed-variable)
+//! ```
+//!
+//! Unused locals, unreachable code after `return` and assignments in conditions
+//! are not linted: Lox has no local variables, `return`, `if` or `while` yet,
+//! so no script could contain them. Those lints belong with the features.
+
+use crate::ast::{self, BinaryOp, Expr, ExprKind, Program, Span, Stmt, StmtKind};
+use crate::prelude;
+use crate::scanner::Scanner;
+use crate::token::TokenType;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UndefinedGlobal,
+    StringComparison,
+    ShadowedVariable,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 3] = [
+        Lint::UndefinedGlobal,
+        Lint::StringComparison,
+        Lint::ShadowedVariable,
+    ];
+
+    /// the name used in diagnostics and `allow` comments
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UndefinedGlobal => "undefined-global",
+            Lint::StringComparison => "string-comparison",
+            Lint::ShadowedVariable => "shadowed-variable",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Span,
+    /// 1-based column of the start of the span, in characters
+    pub column: usize,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: warning[{}]: {}",
+            self.span.line,
+            self.column,
+            self.lint.name(),
+            self.message
+        )
+    }
+}
+
+/// Lints a program, in source order. Returns `None` if it does not parse,
+/// after reporting the errors to stderr like the compiler.
+pub fn lint(source: &str) -> Option<Vec<Diagnostic>> {
+    let program = ast::parse(source)?;
+    Some(check(source, &program))
+}
+
+/// Lints a program already parsed from `source`
+pub fn check(source: &str, program: &Program) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        globals: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+    for stmt in &program.statements {
+        linter.statement(stmt);
+    }
+
+    let allowed = allowed(source);
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.retain(|d| !allowed.contains(&(d.span.line, d.lint)));
+    for diagnostic in diagnostics.iter_mut() {
+        let line_start = source[..diagnostic.span.start]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        diagnostic.column = source[line_start..diagnostic.span.start].chars().count() + 1;
+    }
+    diagnostics
+}
+
+/// the lints allowed on each line by `lint: allow(...)` comments
+fn allowed(source: &str) -> HashSet<(u32, Lint)> {
+    let mut allowed = HashSet::new();
+    let mut scanner = Scanner::with_comments(source);
+    let mut last_code_line = 0;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Eof => return allowed,
+            TokenType::Comment => (),
+            _ => {
+                last_code_line = token.line;
+                continue;
+            }
+        }
+
+        let names = token.lexeme[2..]
+            .trim()
+            .strip_prefix("lint:")
+            .map(str::trim)
+            .and_then(|rest| rest.strip_prefix("allow("))
+            .and_then(|rest| rest.strip_suffix(')'));
+        let Some(names) = names else {
+            continue;
+        };
+
+        // a comment on its own line applies to the line below
+        let line = if last_code_line == token.line {
+            token.line
+        } else {
+            token.line + 1
+        };
+        for lint in names
+            .split(',')
+            .filter_map(|name| Lint::from_name(name.trim()))
+        {
+            allowed.insert((line, lint));
+        }
+    }
+}
+
+struct Linter {
+    /// globals defined so far, and the line of their first definition
+    globals: HashMap<String, u32>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter {
+    fn report(&mut self, lint: Lint, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            lint,
+            message,
+            span,
+            column: 0,
+        });
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt.kind {
+            StmtKind::Print(ref expr) | StmtKind::Expression(ref expr) => self.expression(expr),
+            StmtKind::Var {
+                ref name,
+                name_span,
+                ref initializer,
+            } => {
+                // the initializer runs before the variable is defined
+                if let Some(expr) = initializer {
+                    self.expression(expr);
+                }
+
+                if let Some(&line) = self.globals.get(name) {
+                    self.report(
+                        Lint::ShadowedVariable,
+                        name_span,
+                        format!("'{}' redefines the global defined on line {}.", name, line),
+                    );
+                } else if prelude::is_builtin(name) {
+                    self.report(
+                        Lint::ShadowedVariable,
+                        name_span,
+                        format!("'{}' redefines a built-in function.", name),
+                    );
+                }
+                self.globals
+                    .entry(name.to_owned())
+                    .or_insert(name_span.line);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::Nil => (),
+            ExprKind::Variable(ref name) => {
+                if !self.globals.contains_key(name) && !prelude::is_builtin(name) {
+                    self.report(
+                        Lint::UndefinedGlobal,
+                        expr.span,
+                        format!("'{}' is read before it is defined.", name),
+                    );
+                }
+            }
+            ExprKind::Grouping(ref inner) => self.expression(inner),
+            ExprKind::Unary { ref operand, .. } => self.expression(operand),
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.comparison(op, left, right, expr.span);
+            }
+            ExprKind::Map(ref entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExprKind::Index {
+                ref target,
+                ref index,
+            } => {
+                self.expression(target);
+                self.expression(index);
+            }
+            ExprKind::SetIndex {
+                ref target,
+                ref index,
+                ref value,
+            } => {
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+            }
+            ExprKind::Call {
+                ref callee,
+                ref args,
+            } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+        }
+    }
+
+    fn comparison(&mut self, op: BinaryOp, left: &Expr, right: &Expr, span: Span) {
+        use BinaryOp::*;
+
+        let (left, right) = (literal_kind(left), literal_kind(right));
+        if left != Some("string") && right != Some("string") {
+            return;
+        }
+
+        let message = match op {
+            // only numbers can be ordered
+            Greater | GreaterEqual | Lesser | LesserEqual => {
+                format!(
+                    "Strings cannot be compared with '{}'. This always fails at runtime.",
+                    op
+                )
+            }
+            Equal | NotEqual => match (left, right) {
+                (Some(left), Some(right)) if left != right => format!(
+                    "A {} is never equal to a {}, so '{}' is always {}.",
+                    left,
+                    right,
+                    op,
+                    op == NotEqual
+                ),
+                _ => return,
+            },
+            _ => return,
+        };
+        self.report(Lint::StringComparison, span, message);
+    }
+}
+
+/// the type of a literal expression
+fn literal_kind(expr: &Expr) -> Option<&'static str> {
+    match expr.kind {
+        ExprKind::Number(_) => Some("number"),
+        ExprKind::String(_) => Some("string"),
+        ExprKind::Bool(_) => Some("boolean"),
+        ExprKind::Nil => Some("nil"),
+        ExprKind::Map(_) => Some("map"),
+        ExprKind::Grouping(ref inner) => literal_kind(inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(source: &str) -> Vec<(u32, Lint)> {
+        lint(source)
+            .expect("Failed to parse.")
+            .into_iter()
+            .map(|d| (d.span.line, d.lint))
+            .collect()
+    }
+
+    #[test]
+    fn undefined_globals() {
+        assert_eq!(
+            lints("print a;\nvar a = a;\nprint a + len(\"x\");\nb[1] = 2;"),
+            vec![
+                (1, Lint::UndefinedGlobal),
+                (2, Lint::UndefinedGlobal),
+                (4, Lint::UndefinedGlobal),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_comparisons() {
+        assert_eq!(
+            lints("var s = \"a\";\nprint s == \"a\";\nprint s < \"b\";\nprint 1 != (\"1\");\nprint \"a\" == \"b\";"),
+            vec![(3, Lint::StringComparison), (4, Lint::StringComparison)]
+        );
+    }
+
+    #[test]
+    fn shadowing() {
+        assert_eq!(
+            lints("var a = 1;\nprint a;\nvar a = 2;\nprint a;\nvar len = 3;"),
+            vec![(3, Lint::ShadowedVariable), (5, Lint::ShadowedVariable)]
+        );
+    }
+
+    #[test]
+    fn suppression() {
+        let source = "print a; // lint: allow(undefined-global)\n\
+                      // lint: allow(shadowed-variable, undefined-global)\n\
+                      var b = b;\n\
+                      var b = c; // lint: allow(string-comparison)";
+        assert_eq!(
+            lints(source),
+            vec![(4, Lint::UndefinedGlobal), (4, Lint::ShadowedVariable)]
+        );
+    }
+
+    #[test]
+    fn render() {
+        let diagnostics = lint("var x = 1;\n  print \"x\" >= x;").unwrap();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "2:9: warning[string-comparison]: \
+             Strings cannot be compared with '>='. This always fails at runtime."
+        );
+    }
+}
@@ -0,0 +1,738 @@
+//! A language server over stdio, behind `rustox lsp`.
+//!
+//! Documents are synced in full on every change, and each change is answered
+//! with diagnostics: syntax errors from the parser, or lint warnings if the
+//! document parses. The server also provides
+//!
+//! - semantic tokens, classified like the highlighter's from a comment-keeping scan
+//! - go-to-definition and hover for variables, which are all globals for now
+//! - document symbols for every `var` declaration
+//!
+//! Positions are in UTF-16 code units, the protocol's default encoding.
+
+use crate::ast::{self, Expr, ExprKind, Program, Span, Stmt, StmtKind};
+use crate::highlight::TokenClass;
+use crate::json::Json;
+use crate::lint;
+use crate::prelude;
+use crate::scanner::Scanner;
+use crate::token::TokenType;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+// error codes from the JSON-RPC and LSP specifications
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_NOT_INITIALIZED: i32 = -32002;
+
+/// the semantic token types, indexed by `token_class`
+const TOKEN_TYPES: [&str; 6] = [
+    "keyword", "variable", "string", "number", "operator", "comment",
+];
+
+// severities and symbol kinds from the LSP specification
+const SEVERITY_ERROR: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+const SYMBOL_VARIABLE: u32 = 13;
+
+/// Serves requests from `input` until the client sends `exit` or closes it.
+/// Returns whether the client asked to shut down first, as an exit status should reflect.
+pub fn serve(input: impl BufRead, output: impl Write) -> io::Result<bool> {
+    let mut server = Server {
+        input,
+        output,
+        documents: HashMap::new(),
+        initialized: false,
+        shutdown: false,
+    };
+
+    while let Some(body) = server.read_message()? {
+        match Json::parse(&body) {
+            Ok(message) => {
+                if message.get("method").and_then(Json::as_str) == Some("exit") {
+                    break;
+                }
+                server.handle(&message)?;
+            }
+            Err(e) => server.respond(Json::Null, Err((PARSE_ERROR, e)))?,
+        }
+    }
+    Ok(server.shutdown)
+}
+
+struct Server<R, W> {
+    input: R,
+    output: W,
+    /// the text of each open document, by uri
+    documents: HashMap<String, String>,
+    initialized: bool,
+    shutdown: bool,
+}
+
+type Response = Result<Json, (i32, String)>;
+
+impl<R: BufRead, W: Write> Server<R, W> {
+    /// reads the body of the next message, or `None` at the end of input
+    fn read_message(&mut self) -> io::Result<Option<String>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse().ok();
+                }
+            }
+        }
+
+        let length = length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header.")
+        })?;
+        let mut body = vec![0; length];
+        self.input.read_exact(&mut body)?;
+        String::from_utf8(body)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, id: Json, response: Response) -> io::Result<()> {
+        let outcome = match response {
+            Ok(result) => ("result", result),
+            Err((code, message)) => (
+                "error",
+                Json::object([
+                    ("code", Json::Number(code as f64)),
+                    ("message", message.into()),
+                ]),
+            ),
+        };
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id),
+            outcome,
+        ]))
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]))
+    }
+
+    fn handle(&mut self, message: &Json) -> io::Result<()> {
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let method = message.get("method").and_then(Json::as_str);
+
+        match (method, message.get("id")) {
+            (Some(method), Some(id)) => {
+                let response = self.request(method, params);
+                self.respond(id.clone(), response)
+            }
+            (Some(method), None) => self.notification(method, params),
+            // a response, but the server never sends requests
+            (None, _) => Ok(()),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Response {
+        if method == "initialize" {
+            self.initialized = true;
+            return Ok(capabilities());
+        }
+        if !self.initialized {
+            return Err((
+                SERVER_NOT_INITIALIZED,
+                "The server is not initialized.".to_owned(),
+            ));
+        }
+        if self.shutdown {
+            return Err((INVALID_REQUEST, "The server is shutting down.".to_owned()));
+        }
+
+        match method {
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/semanticTokens/full" => {
+                let (_, source) = self.document(params)?;
+                Ok(Json::object([("data", semantic_tokens(source).into())]))
+            }
+            "textDocument/documentSymbol" => {
+                let (_, source) = self.document(params)?;
+                Ok(document_symbols(source))
+            }
+            "textDocument/definition" => {
+                let (uri, source) = self.document(params)?;
+                let offset = offset_param(source, params)?;
+                Ok(definition(uri, source, offset))
+            }
+            "textDocument/hover" => {
+                let (_, source) = self.document(params)?;
+                let offset = offset_param(source, params)?;
+                Ok(self.hover(source, offset))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'.", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let document = params.get("textDocument");
+        let Some(uri) = document.and_then(|d| d.get("uri")).and_then(Json::as_str) else {
+            return Ok(());
+        };
+        let uri = uri.to_owned();
+
+        let text = match method {
+            "textDocument/didOpen" => document.and_then(|d| d.get("text")),
+            // changes are synced in full, so the last one holds the whole text
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .and_then(Json::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text")),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                let params = Json::object([("uri", uri.into()), ("diagnostics", vec![].into())]);
+                return self.notify("textDocument/publishDiagnostics", params);
+            }
+            _ => None,
+        };
+        let Some(text) = text.and_then(Json::as_str) else {
+            return Ok(());
+        };
+
+        let diagnostics = diagnostics(text);
+        self.documents.insert(uri.clone(), text.to_owned());
+        let params = Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]);
+        self.notify("textDocument/publishDiagnostics", params)
+    }
+
+    /// the uri and text of the open document named in `params`
+    fn document<'a>(&'a self, params: &'a Json) -> Result<(&'a str, &'a str), (i32, String)> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .ok_or_else(|| (INVALID_PARAMS, "Missing textDocument.uri.".to_owned()))?;
+        let source = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("'{}' is not open.", uri)))?;
+        Ok((uri, source))
+    }
+
+    fn hover(&self, source: &str, offset: usize) -> Json {
+        let Ok(program) = ast::parse_quietly(source) else {
+            return Json::Null;
+        };
+        let symbols = Symbols::of(&program);
+        let Some((name, span)) = symbols.name_at(offset) else {
+            return Json::Null;
+        };
+
+        let lines: Vec<String> = symbols
+            .declarations
+            .iter()
+            .filter(|decl| decl.name == name)
+            .map(|decl| decl.name_span.line.to_string())
+            .collect();
+        let text = match (lines.len(), prelude::arity(name)) {
+            (0, Some(arity)) => format!(
+                "```lox\n{}\n```\nBuilt-in function taking {} argument{}.",
+                name,
+                arity,
+                if arity == 1 { "" } else { "s" }
+            ),
+            (0, _) => format!("```lox\n{}\n```\nNever declared.", name),
+            (1, _) => format!("```lox\nvar {}\n```\nDeclared on line {}.", name, lines[0]),
+            _ => format!(
+                "```lox\nvar {}\n```\nDeclared on lines {}.",
+                name,
+                lines.join(", ")
+            ),
+        };
+
+        Json::object([
+            (
+                "contents",
+                Json::object([("kind", "markdown".into()), ("value", text.into())]),
+            ),
+            ("range", LineIndex::new(source).range(span.start, span.end)),
+        ])
+    }
+}
+
+fn capabilities() -> Json {
+    let legend = Json::object([
+        (
+            "tokenTypes",
+            TOKEN_TYPES
+                .iter()
+                .map(|&t| t.into())
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        ("tokenModifiers", vec![].into()),
+    ]);
+
+    Json::object([
+        (
+            "capabilities",
+            Json::object([
+                // full sync
+                ("textDocumentSync", 1u32.into()),
+                (
+                    "semanticTokensProvider",
+                    Json::object([("legend", legend), ("full", true.into())]),
+                ),
+                ("definitionProvider", true.into()),
+                ("hoverProvider", true.into()),
+                ("documentSymbolProvider", true.into()),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object([
+                ("name", "rustox".into()),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ]),
+        ),
+    ])
+}
+
+/// the byte offset of the `position` in `params`
+fn offset_param(source: &str, params: &Json) -> Result<usize, (i32, String)> {
+    let position = params.get("position");
+    let field = |name| position.and_then(|p| p.get(name)).and_then(Json::as_f64);
+    match (field("line"), field("character")) {
+        (Some(line), Some(character)) => {
+            Ok(LineIndex::new(source).offset(line as usize, character as usize))
+        }
+        _ => Err((INVALID_PARAMS, "Missing position.".to_owned())),
+    }
+}
+
+fn diagnostics(source: &str) -> Vec<Json> {
+    let index = LineIndex::new(source);
+    let diagnostic = |start, end, severity: u32, message: &str, code: Option<&str>| {
+        let mut members = vec![
+            ("range", index.range(start, end)),
+            ("severity", severity.into()),
+            ("source", "rustox".into()),
+            ("message", message.into()),
+        ];
+        if let Some(code) = code {
+            members.push(("code", code.into()));
+        }
+        Json::object(members)
+    };
+
+    match ast::parse_quietly(source) {
+        Err(errors) => errors
+            .iter()
+            .map(|e| diagnostic(e.start, e.end, SEVERITY_ERROR, e.message, None))
+            .collect(),
+        Ok(program) => lint::check(source, &program)
+            .iter()
+            .map(|d| {
+                let (start, end) = (d.span.start, d.span.end);
+                diagnostic(
+                    start,
+                    end,
+                    SEVERITY_WARNING,
+                    &d.message,
+                    Some(d.lint.name()),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// the index into `TOKEN_TYPES` for a token, or `None` for punctuation.
+/// `true`, `false` and `nil` are shown as keywords, as editors expect
+fn token_class(token_type: TokenType) -> Option<usize> {
+    match TokenClass::of(token_type)? {
+        TokenClass::Keyword => Some(0),
+        TokenClass::Identifier => Some(1),
+        TokenClass::Literal => match token_type {
+            TokenType::String => Some(2),
+            TokenType::Number => Some(3),
+            _ => Some(0),
+        },
+        TokenClass::Operator => Some(4),
+        TokenClass::Comment => Some(5),
+        TokenClass::Punctuation => None,
+    }
+}
+
+/// the relative encoding of semantic tokens, five numbers per token.
+/// a token spanning lines is split into one per line
+fn semantic_tokens(source: &str) -> Vec<Json> {
+    let index = LineIndex::new(source);
+    let mut scanner = Scanner::with_comments(source);
+    let mut data = Vec::new();
+    let (mut last_line, mut last_character) = (0, 0);
+
+    loop {
+        let token = scanner.scan_token();
+        if token.token_type == TokenType::Eof {
+            return data;
+        }
+        let Some(class) = token_class(token.token_type) else {
+            continue;
+        };
+
+        let end = token.start + token.lexeme.len();
+        for (start, end) in index.split_lines(token.start, end) {
+            let (line, character) = index.position(start);
+            let length = source[start..end].encode_utf16().count();
+            if line != last_line {
+                last_character = 0;
+            }
+            data.extend([
+                (line - last_line).into(),
+                (character - last_character).into(),
+                length.into(),
+                class.into(),
+                0u32.into(),
+            ]);
+            (last_line, last_character) = (line, character);
+        }
+    }
+}
+
+fn document_symbols(source: &str) -> Json {
+    let Ok(program) = ast::parse_quietly(source) else {
+        return Json::Null;
+    };
+    let index = LineIndex::new(source);
+
+    let symbols = Symbols::of(&program)
+        .declarations
+        .iter()
+        .map(|decl| {
+            Json::object([
+                ("name", decl.name.into()),
+                ("kind", SYMBOL_VARIABLE.into()),
+                ("range", index.range(decl.span.start, decl.span.end)),
+                (
+                    "selectionRange",
+                    index.range(decl.name_span.start, decl.name_span.end),
+                ),
+            ])
+        })
+        .collect::<Vec<_>>();
+    symbols.into()
+}
+
+/// The declaration a variable at `offset` refers to. Globals are defined as
+/// statements run, so this is the last declaration before it, if any
+fn definition(uri: &str, source: &str, offset: usize) -> Json {
+    let Ok(program) = ast::parse_quietly(source) else {
+        return Json::Null;
+    };
+    let symbols = Symbols::of(&program);
+    let Some((name, span)) = symbols.name_at(offset) else {
+        return Json::Null;
+    };
+
+    // a declaration is its own definition, and otherwise takes effect after its initializer
+    let mut declarations = symbols.declarations.iter().filter(|decl| decl.name == name);
+    let first = declarations.clone().next();
+    let Some(decl) = declarations
+        .rfind(|decl| decl.name_span == span || decl.span.end <= span.start)
+        .or(first)
+    else {
+        return Json::Null;
+    };
+
+    let range = LineIndex::new(source).range(decl.name_span.start, decl.name_span.end);
+    Json::object([("uri", uri.into()), ("range", range)])
+}
+
+struct Declaration<'a> {
+    name: &'a str,
+    name_span: Span,
+    /// the whole `var` statement
+    span: Span,
+}
+
+/// every variable declared and used in a program, in source order
+struct Symbols<'a> {
+    declarations: Vec<Declaration<'a>>,
+    references: Vec<(&'a str, Span)>,
+}
+
+impl<'a> Symbols<'a> {
+    fn of(program: &'a Program) -> Self {
+        let mut symbols = Symbols {
+            declarations: Vec::new(),
+            references: Vec::new(),
+        };
+        for stmt in &program.statements {
+            symbols.statement(stmt);
+        }
+        symbols
+    }
+
+    /// the variable named at `offset`, including just past its end
+    fn name_at(&self, offset: usize) -> Option<(&'a str, Span)> {
+        let declarations = self.declarations.iter().map(|d| (d.name, d.name_span));
+        declarations
+            .chain(self.references.iter().copied())
+            .find(|(_, span)| span.start <= offset && offset <= span.end)
+    }
+
+    fn statement(&mut self, stmt: &'a Stmt) {
+        match stmt.kind {
+            StmtKind::Print(ref expr) | StmtKind::Expression(ref expr) => self.expression(expr),
+            StmtKind::Var {
+                ref name,
+                name_span,
+                ref initializer,
+            } => {
+                if let Some(expr) = initializer {
+                    self.expression(expr);
+                }
+                self.declarations.push(Declaration {
+                    name,
+                    name_span,
+                    span: stmt.span,
+                });
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &'a Expr) {
+        match expr.kind {
+            ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::Nil => (),
+            ExprKind::Variable(ref name) => self.references.push((name, expr.span)),
+            ExprKind::Grouping(ref inner) => self.expression(inner),
+            ExprKind::Unary { ref operand, .. } => self.expression(operand),
+            ExprKind::Binary {
+                ref left,
+                ref right,
+                ..
+            } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Map(ref entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExprKind::Index {
+                ref target,
+                ref index,
+            } => {
+                self.expression(target);
+                self.expression(index);
+            }
+            ExprKind::SetIndex {
+                ref target,
+                ref index,
+                ref value,
+            } => {
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+            }
+            ExprKind::Call {
+                ref callee,
+                ref args,
+            } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+        }
+    }
+}
+
+/// Converts between byte offsets and protocol positions
+struct LineIndex<'a> {
+    source: &'a str,
+    /// the byte offset each line starts at
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(source: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { source, starts }
+    }
+
+    /// the zero-based line and UTF-16 column of a byte offset
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let character = self.source[self.starts[line]..offset]
+            .encode_utf16()
+            .count();
+        (line, character)
+    }
+
+    /// the byte offset of a position, clamped to the end of its line
+    fn offset(&self, line: usize, character: usize) -> usize {
+        let Some(&start) = self.starts.get(line) else {
+            return self.source.len();
+        };
+        let end = self.line_end(line);
+
+        let mut units = 0;
+        for (i, c) in self.source[start..end].char_indices() {
+            if units >= character {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        end
+    }
+
+    /// the end of a line, before its newline
+    fn line_end(&self, line: usize) -> usize {
+        self.starts
+            .get(line + 1)
+            .map_or(self.source.len(), |&next| next - 1)
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        let position = |offset| {
+            let (line, character) = self.position(offset);
+            Json::object([("line", line.into()), ("character", character.into())])
+        };
+        Json::object([("start", position(start)), ("end", position(end))])
+    }
+
+    /// the non-empty parts of the range on each line it covers
+    fn split_lines(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let (first, _) = self.position(start);
+        let (last, _) = self.position(end);
+        (first..=last)
+            .map(|line| {
+                let from = start.max(self.starts[line]);
+                (from, end.min(self.line_end(line)).max(from))
+            })
+            .filter(|(from, to)| from < to)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let source = "var é = 1;\nprint \"😀\" + é;\n";
+        let index = LineIndex::new(source);
+
+        let second = source.find('+').unwrap();
+        assert_eq!(index.position(second), (1, 11));
+        assert_eq!(index.offset(1, 11), second);
+        // past the end of a line or the source
+        assert_eq!(index.offset(0, 40), source.find('\n').unwrap());
+        assert_eq!(index.offset(5, 0), source.len());
+
+        let string = source.find('"').unwrap();
+        let multiline = "\"a\nbc\"";
+        assert_eq!(index.split_lines(string, string + 6).len(), 1);
+        assert_eq!(
+            LineIndex::new(multiline).split_lines(0, multiline.len()),
+            vec![(0, 2), (3, 6)]
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        let data: Vec<Json> = semantic_tokens("var a = 1; // c\n  print \"x\n\";");
+        let data: Vec<u32> = data.iter().map(|n| n.as_f64().unwrap() as u32).collect();
+        assert_eq!(
+            data,
+            vec![
+                0, 0, 3, 0, 0, // var
+                0, 4, 1, 1, 0, // a
+                0, 2, 1, 4, 0, // =
+                0, 2, 1, 3, 0, // 1
+                0, 3, 4, 5, 0, // // c
+                1, 2, 5, 0, 0, // print
+                0, 6, 2, 2, 0, // "x
+                1, 0, 1, 2, 0, // "
+            ]
+        );
+    }
+
+    #[test]
+    fn definitions() {
+        let source = "var a = 1;\nprint a;\nvar a = a + 2;\nprint a;\nprint b;";
+        let target = |offset| {
+            let location = definition("file:///t.lox", source, offset);
+            location
+                .get("range")
+                .and_then(|r| r.get("start"))
+                .and_then(|s| s.get("line"))
+                .and_then(Json::as_f64)
+        };
+
+        // a use on line 2, the initializer on line 3 and a use on line 4
+        assert_eq!(target(source.find("print a").unwrap() + 6), Some(0.0));
+        assert_eq!(target(source.find("a + 2").unwrap()), Some(0.0));
+        assert_eq!(target(source.rfind("print a").unwrap() + 7), Some(2.0));
+        assert_eq!(target(source.rfind('b').unwrap()), None);
+        assert_eq!(target(source.rfind("var a").unwrap() + 4), Some(2.0));
+        assert_eq!(target(0), None);
+    }
+
+    #[test]
+    fn symbols_and_diagnostics() {
+        let symbols = document_symbols("var a;\nvar b = a;");
+        let names: Vec<&str> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s.get("name").and_then(Json::as_str).unwrap())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+
+        let errors = diagnostics("print 1\nprint 2;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get("severity"), Some(&Json::Number(1.0)));
+        assert_eq!(
+            errors[0].get("message").and_then(Json::as_str),
+            Some("Expected ';' after print statement.")
+        );
+
+        let warnings = diagnostics("print x;");
+        assert_eq!(
+            warnings[0].get("code").and_then(Json::as_str),
+            Some("undefined-global")
+        );
+    }
+}
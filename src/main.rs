@@ -1,21 +1,325 @@
 use rustox::chunk::Chunk;
 use rustox::compiler::Compiler;
-use rustox::vm::Vm;
+use rustox::coverage::{Coverage, FileCoverage};
+use rustox::debugger::Debugger;
+use rustox::formatter::{self, FormatOptions};
+use rustox::highlight;
+use rustox::lint;
+use rustox::lsp;
+use rustox::serialize::MAGIC;
+use rustox::testing::{self, Expectations};
+use rustox::vm::{InterpretResult, Vm};
+use std::process::exit;
+
+// exit codes from sysexits.h, as used by clox
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+/// subcommands, which a lone argument is not taken as a script path for
+const COMMANDS: [&str; 8] = [
+    "compile",
+    "fmt",
+    "lint",
+    "lsp",
+    "highlight",
+    "debug",
+    "profile",
+    "test",
+];
+
+const USAGE: &str = "\
+Usage: rustox [run] [--trace] <script.lox | script.loxc>
+       rustox compile <script.lox> -o <script.loxc>
+       rustox fmt [--check] [--indent <width>] <script.lox>...
+       rustox lint <script.lox>...
+       rustox lsp
+       rustox highlight [--html | --ansi] <script.lox>
+       rustox debug <script.lox>
+       rustox profile [--collapsed <out.folded>] <script.lox | script.loxc>
+       rustox test [--coverage] [--lcov <out.info>] <script.lox | dir>...";
 
 fn main() {
-    let source = r#"var beverage = "cafe au lait";
-    var breakfast = "beignets with " + beverage;
-    print breakfast;
-    "#;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["compile", input, "-o", output] => compile_file(input, output),
+        ["fmt", rest @ ..] => fmt_files(rest),
+        ["lint", paths @ ..] if !paths.is_empty() => lint_files(paths),
+        ["lsp"] => serve_lsp(),
+        ["debug", path] => debug_file(path),
+        ["profile", path] => profile_file(path, None),
+        ["profile", "--collapsed", output, path] => profile_file(path, Some(output)),
+        ["test", rest @ ..] => test_files(rest),
+        ["highlight", "--html", path] => print!("{}", highlight::html(&read_source(path))),
+        ["highlight", "--ansi", path] | ["highlight", path] => {
+            print!("{}", highlight::ansi(&read_source(path)))
+        }
+        ["run", "--trace", path] | ["--trace", path] => run_file(path, true),
+        ["run", path] => run_file(path, false),
+        [path] if !path.starts_with('-') && !COMMANDS.contains(path) => run_file(path, false),
+        _ => usage(),
+    }
+}
+
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Could not read \"{}\": {}", path, e);
+        exit(EX_IOERR);
+    })
+}
+
+fn read_source(path: &str) -> String {
+    String::from_utf8(read(path)).unwrap_or_else(|_| {
+        eprintln!("\"{}\" is not valid utf-8.", path);
+        exit(EX_DATAERR);
+    })
+}
+
+fn compile(path: &str, bytes: Vec<u8>) -> Chunk {
+    let source = String::from_utf8(bytes).unwrap_or_else(|_| {
+        eprintln!("\"{}\" is not valid utf-8.", path);
+        exit(EX_DATAERR);
+    });
+
+    let mut compiler = Compiler::new(&source);
+    if compiler.compile(Chunk::new()) {
+        exit(EX_DATAERR);
+    }
+    compiler.take_chunk()
+}
+
+/// loads either bytecode or source, telling them apart by the magic header
+fn load(path: &str) -> Chunk {
+    let bytes = read(path);
+    if !bytes.starts_with(MAGIC) {
+        return compile(path, bytes);
+    }
+
+    Chunk::from_bytes(&bytes).unwrap_or_else(|e| {
+        eprintln!("Could not load \"{}\": {}", path, e);
+        exit(EX_DATAERR);
+    })
+}
+
+fn run_file(path: &str, trace: bool) {
+    let chunk = load(path);
     let mut vm = Vm::new();
-    let mut comp = Compiler::new(source);
-    let chunk = Chunk::new();
-    let had_error = comp.compile(chunk);
-    if had_error {
-        return;
+    vm.set_tracing(trace);
+    match vm.interpret(chunk) {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError => exit(EX_DATAERR),
+        InterpretResult::RuntimeError => exit(EX_SOFTWARE),
+    }
+}
+
+/// runs a script under the debugger, reading commands from stdin
+fn debug_file(path: &str) {
+    let source = read_source(path);
+    let chunk = compile(path, source.clone().into_bytes());
+
+    let mut vm = Vm::new();
+    vm.set_debug_hook(Debugger::new(
+        &source,
+        std::io::stdin().lock(),
+        std::io::stdout(),
+    ));
+    match vm.interpret(chunk) {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError => exit(EX_DATAERR),
+        InterpretResult::RuntimeError => exit(EX_SOFTWARE),
+    }
+}
+
+/// runs a script with profiling on, then reports the counts to stderr
+/// and optionally writes them as collapsed stacks for flamegraph tools
+fn profile_file(path: &str, collapsed: Option<&str>) {
+    let chunk = load(path);
+    let mut vm = Vm::new();
+    vm.set_profiling(true);
+    let result = vm.interpret(chunk);
+
+    let profile = vm.take_profile().expect("Profiling is on.");
+    // bytecode files have no source to show
+    let source = std::fs::read_to_string(path)
+        .ok()
+        .filter(|source| !source.as_bytes().starts_with(MAGIC));
+    eprint!("{}", profile.report(source.as_deref()));
+
+    if let Some(output) = collapsed {
+        if let Err(e) = std::fs::write(output, profile.collapsed(path)) {
+            eprintln!("Could not write \"{}\": {}", output, e);
+            exit(EX_IOERR);
+        }
+    }
+
+    match result {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError => exit(EX_DATAERR),
+        InterpretResult::RuntimeError => exit(EX_SOFTWARE),
+    }
+}
+
+/// checks scripts against their annotations, failing if any do not match.
+/// With `--coverage` it also writes the lines they ran as an lcov tracefile,
+/// `lcov.info` unless `--lcov` names another, and summarizes them on stderr.
+fn test_files(args: &[&str]) {
+    let mut lcov = None;
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--coverage" => lcov = lcov.or(Some("lcov.info")),
+            "--lcov" => lcov = Some(*args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') => usage(),
+            path => paths.push(path),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut scripts = Vec::new();
+    for path in paths {
+        if let Err(e) = testing::find_scripts(path.as_ref(), &mut scripts) {
+            eprintln!("Could not read \"{}\": {}", path, e);
+            exit(EX_IOERR);
+        }
+    }
+    scripts.sort();
+
+    let mut coverage = Coverage::default();
+    let mut failed = 0;
+    for script in &scripts {
+        let path = script.to_string_lossy();
+        let source = read_source(&path);
+        let run = testing::run(&source);
+
+        let failures = Expectations::parse(&source).check(&run.outcome);
+        if failures.is_empty() {
+            println!("PASS {}", path);
+        } else {
+            failed += 1;
+            println!("FAIL {}", path);
+            for failure in failures {
+                println!("     {}", failure);
+            }
+        }
+
+        if let (Some(chunk), Some(profile)) = (&run.chunk, &run.profile) {
+            coverage.add(FileCoverage::new(&path, chunk, profile));
+        }
+    }
+    println!("\n{} passed, {} failed.", scripts.len() - failed, failed);
+
+    if let Some(output) = lcov {
+        eprint!("\n{}", coverage.summary());
+        if let Err(e) = std::fs::write(output, coverage.lcov()) {
+            eprintln!("Could not write \"{}\": {}", output, e);
+            exit(EX_IOERR);
+        }
+    }
+
+    if failed > 0 {
+        exit(1);
     }
+}
+
+fn compile_file(input: &str, output: &str) {
+    let chunk = compile(input, read(input));
+    let bytes = chunk.to_bytes().unwrap_or_else(|e| {
+        eprintln!("Could not serialize \"{}\": {}", input, e);
+        exit(EX_SOFTWARE);
+    });
 
-    let chunk = comp.take_chunk();
-    chunk.disas("Code");
-    vm.interpret(chunk);
+    if let Err(e) = std::fs::write(output, bytes) {
+        eprintln!("Could not write \"{}\": {}", output, e);
+        exit(EX_IOERR);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(EX_USAGE);
+}
+
+/// formats files in place, or with `--check` only lists the files that would change
+fn fmt_files(args: &[&str]) {
+    let mut check = false;
+    let mut options = FormatOptions::default();
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--check" => check = true,
+            "--indent" => {
+                options.indent = match args.next().map(|width| width.parse()) {
+                    Some(Ok(width)) => width,
+                    _ => usage(),
+                }
+            }
+            _ if arg.starts_with('-') => usage(),
+            path => paths.push(path),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut unformatted = false;
+    for path in paths {
+        let source = read_source(path);
+        let formatted = formatter::format(&source, &options).unwrap_or_else(|| exit(EX_DATAERR));
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("Would reformat \"{}\"", path);
+            unformatted = true;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("Could not write \"{}\": {}", path, e);
+            exit(EX_IOERR);
+        }
+    }
+
+    if unformatted {
+        exit(1);
+    }
+}
+
+/// prints the lint warnings in each file, failing if there were any
+fn lint_files(paths: &[&str]) {
+    let mut warned = false;
+    for path in paths {
+        let source = read_source(path);
+        let diagnostics = lint::lint(&source).unwrap_or_else(|| exit(EX_DATAERR));
+        for diagnostic in diagnostics {
+            println!("{}:{}", path, diagnostic);
+            warned = true;
+        }
+    }
+
+    if warned {
+        exit(1);
+    }
+}
+
+/// runs the language server on stdio, until the client exits
+fn serve_lsp() {
+    let stdin = std::io::stdin().lock();
+    let stdout = std::io::stdout().lock();
+    match lsp::serve(stdin, stdout) {
+        Ok(true) => (),
+        // the client exited without asking to shut down
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("Language server failed: {}", e);
+            exit(EX_IOERR);
+        }
+    }
 }
@@ -0,0 +1,228 @@
+//! NaN-boxed value representation, enabled with the `nan-boxing` feature.
+//!
+//! Every `Value` is packed into a single `u64`:
+//! - numbers are stored as their own bits (NaNs are canonicalized)
+//! - nil and booleans are quiet NaNs with a small tag in the low bits
+//! - objects are quiet NaNs with the sign bit set, a 2 bit object kind
+//!   in bits 48..50, and the `Rc` pointer in the low 48 bits
+
+use crate::value::{Map, NativeFn, StackSlot, Value};
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const KIND_SHIFT: u32 = 48;
+const KIND_MASK: u64 = 0b11 << KIND_SHIFT;
+const PTR_MASK: u64 = (1 << KIND_SHIFT) - 1;
+
+const KIND_STRING: u64 = 0;
+const KIND_IDENT: u64 = 1;
+const KIND_MAP: u64 = 2;
+const KIND_NATIVE: u64 = 3;
+
+pub struct NanBox {
+    bits: u64,
+    // owns reference counts of `Rc`s, so it must not cross threads
+    _marker: PhantomData<Rc<()>>,
+}
+
+impl NanBox {
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        NanBox {
+            bits,
+            _marker: PhantomData,
+        }
+    }
+
+    fn object<T>(kind: u64, rc: Rc<T>) -> Self {
+        let ptr = Rc::into_raw(rc) as u64;
+        assert_eq!(ptr & !PTR_MASK, 0, "Pointer does not fit in 48 bits.");
+        Self::from_bits(SIGN_BIT | QNAN | (kind << KIND_SHIFT) | ptr)
+    }
+
+    #[inline]
+    pub fn is_number(&self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+
+    #[inline]
+    fn is_object(&self) -> bool {
+        self.bits & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN
+    }
+
+    fn kind(&self) -> u64 {
+        (self.bits & KIND_MASK) >> KIND_SHIFT
+    }
+
+    fn ptr<T>(&self) -> *const T {
+        (self.bits & PTR_MASK) as *const T
+    }
+
+    /// Rebuilds the value, taking over the reference held by this box.
+    ///
+    /// # Safety
+    /// The box must not be dropped afterwards.
+    #[inline]
+    unsafe fn read(&self) -> Value {
+        if self.is_number() {
+            return Value::Number(f64::from_bits(self.bits));
+        }
+
+        if !self.is_object() {
+            return match self.bits & !QNAN {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Bool(false),
+                TAG_TRUE => Value::Bool(true),
+                _ => unreachable!("Invalid NaN-boxed value {:#x}", self.bits),
+            };
+        }
+
+        match self.kind() {
+            KIND_STRING => Value::String(Rc::from_raw(self.ptr::<String>())),
+            KIND_IDENT => Value::Ident(Rc::from_raw(self.ptr::<String>())),
+            KIND_MAP => Value::Map(Rc::from_raw(self.ptr::<RefCell<Map>>())),
+            KIND_NATIVE => Value::Native(Rc::from_raw(self.ptr::<NativeFn>())),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl StackSlot for NanBox {
+    #[inline]
+    fn from_value(value: Value) -> Self {
+        match value {
+            Value::Number(n) => Self::from_number(n),
+            Value::Nil => Self::from_bits(QNAN | TAG_NIL),
+            Value::Bool(false) => Self::from_bits(QNAN | TAG_FALSE),
+            Value::Bool(true) => Self::from_bits(QNAN | TAG_TRUE),
+            Value::String(s) => Self::object(KIND_STRING, s),
+            Value::Ident(s) => Self::object(KIND_IDENT, s),
+            Value::Map(m) => Self::object(KIND_MAP, m),
+            Value::Native(n) => Self::object(KIND_NATIVE, n),
+        }
+    }
+
+    #[inline]
+    fn into_value(self) -> Value {
+        // SAFETY: the reference moves into the returned value, so the box is forgotten
+        let value = unsafe { self.read() };
+        std::mem::forget(self);
+        value
+    }
+
+    fn to_value(&self) -> Value {
+        self.clone().into_value()
+    }
+
+    #[inline]
+    fn from_number(n: f64) -> Self {
+        if n.is_nan() {
+            Self::from_bits(f64::NAN.to_bits())
+        } else {
+            Self::from_bits(n.to_bits())
+        }
+    }
+
+    #[inline]
+    fn as_number(&self) -> Option<f64> {
+        if self.is_number() {
+            Some(f64::from_bits(self.bits))
+        } else {
+            None
+        }
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            // SAFETY: the pointer came from `Rc::into_raw` and is kept alive by `self`
+            unsafe {
+                match self.kind() {
+                    KIND_STRING | KIND_IDENT => Rc::increment_strong_count(self.ptr::<String>()),
+                    KIND_MAP => Rc::increment_strong_count(self.ptr::<RefCell<Map>>()),
+                    KIND_NATIVE => Rc::increment_strong_count(self.ptr::<NativeFn>()),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        Self::from_bits(self.bits)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if self.is_object() {
+            // SAFETY: the box owns exactly one reference to the pointer
+            drop(unsafe { self.read() });
+        }
+    }
+}
+
+impl Debug for NanBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_value())
+    }
+}
+
+impl PartialEq<Value> for NanBox {
+    fn eq(&self, other: &Value) -> bool {
+        self.to_value() == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) -> Value {
+        NanBox::from_value(value).into_value()
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(std::mem::size_of::<NanBox>(), 8);
+    }
+
+    #[test]
+    fn immediates() {
+        for v in [
+            Value::Number(1.5),
+            Value::Number(-0f64),
+            Value::Number(f64::INFINITY),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Nil,
+        ] {
+            assert_eq!(round_trip(v.clone()), v);
+        }
+        assert!(matches!(round_trip(Value::Number(f64::NAN)), Value::Number(n) if n.is_nan()));
+    }
+
+    #[test]
+    fn objects() {
+        let s = Rc::new("hi".to_owned());
+        let boxed = NanBox::from_value(Value::String(s.clone()));
+        assert_eq!(Rc::strong_count(&s), 2);
+
+        let copy = boxed.clone();
+        assert_eq!(Rc::strong_count(&s), 3);
+        assert_eq!(copy, Value::String(s.clone()));
+
+        drop(copy);
+        drop(boxed);
+        assert_eq!(Rc::strong_count(&s), 1);
+
+        let map = Value::Map(Rc::new(RefCell::new(Map::new())));
+        assert_eq!(round_trip(map.clone()), map);
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, Copy)]
+use crate::token::TokenType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     Return = 0,
     Constant,
@@ -20,45 +22,87 @@ pub enum Opcode {
     Pop,
     DefineGlobal,
     GetGlobal,
+    BuildMap,
+    GetIndex,
+    SetIndex,
+    Call,
+    Modulo,
+    Power,
+    FloorDivide,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    NotEqual,
+    GreaterEqual,
+    LesserEqual,
     Invalid = 255,
 }
 
+/// every valid opcode, indexed by its byte.
+/// If you're developing, remember to update this when adding an opcode.
+const OPCODES: [Opcode; 36] = {
+    use Opcode::*;
+    [
+        Return, Constant, Negate, Add, Subtract, Multiply, Divide, Nil, True, False, Not,
+        Equal, Greater, Lesser, And, Or, Print, Pop, DefineGlobal, GetGlobal, BuildMap,
+        GetIndex, SetIndex, Call, Modulo, Power, FloorDivide, BitAnd, BitOr, BitXor, BitNot,
+        ShiftLeft, ShiftRight, NotEqual, GreaterEqual, LesserEqual,
+    ]
+};
+
+impl Opcode {
+    #[inline]
+    pub fn decode(byte: u8) -> Option<Opcode> {
+        OPCODES.get(byte as usize).copied()
+    }
+}
+
 impl From<u8> for Opcode {
+    #[inline]
     fn from(v: u8) -> Self {
-        (v as usize).into()
+        match Opcode::decode(v) {
+            Some(opcode) => opcode,
+            None => invalid_byte(v as usize),
+        }
     }
 }
 
 impl From<usize> for Opcode {
     fn from(v: usize) -> Self {
-        use Opcode::*;
-        let lookup_tbl = [
-            Return, Constant, Negate, Add, Subtract, Multiply, Divide, Nil, True, False, Not,
-            Equal, Greater, Lesser, And, Or, Print, Pop, DefineGlobal, GetGlobal
-        ];
-        if v < lookup_tbl.len() {
-            lookup_tbl[v]
-        } else {
-            panic!("Invalid instruction byte {}. If you're developing, perhaps you didn't update your lookup table?", v);
+        match u8::try_from(v).ok().and_then(Opcode::decode) {
+            Some(opcode) => opcode,
+            None => invalid_byte(v),
         }
     }
 }
 
-impl Into<u8> for Opcode {
-    fn into(self) -> u8 {
-        self as u8
+#[cold]
+#[inline(never)]
+fn invalid_byte(v: usize) -> ! {
+    panic!("Invalid instruction byte {}.", v);
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        opcode as u8
     }
 }
 
 impl Opcode {
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         use Opcode::*;
 
         match self {
             Invalid => 0,
             Return | Negate | Add | Subtract | Multiply | Divide | Nil | True | False | Not
-            | Equal | Greater | Lesser | And | Or | Print | Pop => 1,
-            Constant | DefineGlobal | GetGlobal => 2,
+            | Equal | Greater | Lesser | And | Or | Print | Pop | GetIndex | SetIndex | Modulo
+            | Power | FloorDivide | BitAnd | BitOr | BitXor | BitNot | ShiftLeft | ShiftRight
+            | NotEqual | GreaterEqual | LesserEqual => 1,
+            Constant | DefineGlobal | GetGlobal | BuildMap | Call => 2,
         }
     }
 }
@@ -71,9 +115,14 @@ pub enum Precedence {
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
 }
@@ -93,9 +142,14 @@ impl From<usize> for Precedence {
             Precedence::And,
             Precedence::Equality,
             Precedence::Comparison,
+            Precedence::BitOr,
+            Precedence::BitXor,
+            Precedence::BitAnd,
+            Precedence::Shift,
             Precedence::Term,
             Precedence::Factor,
             Precedence::Unary,
+            Precedence::Exponent,
             Precedence::Call,
             Precedence::Primary,
         ];
@@ -108,8 +162,42 @@ impl From<usize> for Precedence {
 }
 
 impl Precedence {
+    /// The precedence of `token_type` as an infix operator, or `None` if it is not one
+    pub fn of_infix(token_type: TokenType) -> Precedence {
+        use TokenType::*;
+
+        match token_type {
+            LeftParen | LeftBracket => Precedence::Call,
+            Minus | Plus => Precedence::Term,
+            Percent | Slash | Star | TildeSlash => Precedence::Factor,
+            StarStar => Precedence::Exponent,
+            Ampersand => Precedence::BitAnd,
+            Pipe => Precedence::BitOr,
+            Caret => Precedence::BitXor,
+            LesserLesser | GreaterGreater => Precedence::Shift,
+            BangEqual | EqualEqual => Precedence::Equality,
+            Greater | GreaterEqual | Lesser | LesserEqual => Precedence::Comparison,
+            And => Precedence::And,
+            Or => Precedence::Or,
+            _ => Precedence::None,
+        }
+    }
+
     pub fn next(&self) -> Precedence {
         let prec_num = *self as usize;
         (prec_num + 1).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_table() {
+        for (byte, opcode) in OPCODES.iter().enumerate() {
+            assert_eq!(*opcode as usize, byte);
+        }
+        assert!(Opcode::decode(OPCODES.len() as u8).is_none());
+    }
+}
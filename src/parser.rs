@@ -1,5 +1,6 @@
 use crate::scanner::Scanner;
 use crate::token::{Token, TokenType};
+use std::fmt::Display;
 
 pub struct Parser<'src> {
     pub scanner: Scanner<'src>,
@@ -7,6 +8,32 @@ pub struct Parser<'src> {
     pub previous: Token,
     pub had_error: bool,
     pub panicking: bool,
+    /// every error reported so far
+    pub errors: Vec<SyntaxError>,
+    /// whether errors are also printed to stderr as they are reported
+    pub print_errors: bool,
+}
+
+/// An error reported while parsing, at the token it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: &'static str,
+    pub line: u32,
+    /// byte offsets of the offending token. empty at the end of the source
+    pub start: usize,
+    pub end: usize,
+    /// how the token is shown in the report, such as " at 'x'"
+    location: String,
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[line {}] Error{}: {}",
+            self.line, self.location, self.message
+        )
+    }
 }
 
 enum ErrorPoint {
@@ -21,6 +48,7 @@ impl<'src> Parser<'src> {
             token_type: TokenType::Eof,
             lexeme: "".to_owned(),
             line: 1,
+            start: 0,
         };
         let current = scanner.scan_token();
 
@@ -30,6 +58,8 @@ impl<'src> Parser<'src> {
             previous: placeholder,
             had_error: false,
             panicking: false,
+            errors: Vec::new(),
+            print_errors: true,
         }
     }
 
@@ -110,15 +140,26 @@ impl<'src> Parser<'src> {
             ErrorPoint::Previous => &mut self.previous,
         };
 
-        eprint!("[line {}] Error", token.line);
-
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", token.lexeme),
+        let (location, end) = match token.token_type {
+            TokenType::Eof => (" at end".to_owned(), token.start),
+            TokenType::Error => (String::new(), token.start),
+            _ => (
+                format!(" at '{}'", token.lexeme),
+                token.start + token.lexeme.len(),
+            ),
+        };
+        let error = SyntaxError {
+            message,
+            line: token.line,
+            start: token.start,
+            end,
+            location,
         };
 
-        eprintln!(": {}", message);
+        if self.print_errors {
+            eprintln!("{}", error);
+        }
+        self.errors.push(error);
         self.had_error = true;
     }
 }
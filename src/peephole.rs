@@ -0,0 +1,160 @@
+//! A peephole optimizer over finished chunks.
+//!
+//! Instructions are copied one by one onto an output list, and each is
+//! matched against the instruction last written:
+//!
+//! - a comparison followed by `Not` is fused into the inverse comparison,
+//!   so `!=`, `>=` and `<=` run as a single instruction
+//! - a value pushed without side effects and popped right away is dropped
+//!   along with its `Pop`
+//!
+//! Because matching is against the output, a removal can expose another
+//! pair further back, and `Nil Nil Pop Pop` disappears entirely.
+//! There are no jump instructions yet, so removing code needs no offsets patched.
+
+use crate::chunk::Chunk;
+use crate::disas::Instruction;
+use crate::opcode::Opcode;
+
+impl Chunk {
+    /// Rewrites the chunk with the peephole optimizations above.
+    /// Every instruction that is kept retains its line, and a fused
+    /// instruction takes the line of the comparison.
+    pub fn optimize(&mut self) {
+        let mut out: Vec<Instruction> = Vec::with_capacity(self.len());
+
+        for inst in self.disassemble() {
+            if let Some(last) = out.last_mut() {
+                match (last.opcode, inst.opcode) {
+                    (comparison, Opcode::Not) if inverse(comparison).is_some() => {
+                        last.opcode = inverse(comparison).unwrap();
+                        continue;
+                    }
+                    (push, Opcode::Pop) if is_pure_push(push) => {
+                        out.pop();
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            out.push(inst);
+        }
+
+        let mut code = Vec::with_capacity(self.len());
+        let mut lines = Vec::with_capacity(self.len());
+        for inst in out {
+            // an invalid opcode is carried over as its raw byte
+            if inst.opcode != Opcode::Invalid {
+                code.push(inst.opcode.into());
+                lines.push(inst.line);
+            }
+            code.extend_from_slice(&inst.operands);
+            lines.extend(inst.operands.iter().map(|_| inst.line));
+        }
+
+        *self = Chunk::from_parts(code, lines, self.constants().to_vec());
+    }
+}
+
+/// the comparison that negates the result of `opcode`.
+/// incomparable operands raise the same error either way
+fn inverse(opcode: Opcode) -> Option<Opcode> {
+    use Opcode::*;
+
+    match opcode {
+        Equal => Some(NotEqual),
+        NotEqual => Some(Equal),
+        Lesser => Some(GreaterEqual),
+        GreaterEqual => Some(Lesser),
+        Greater => Some(LesserEqual),
+        LesserEqual => Some(Greater),
+        _ => None,
+    }
+}
+
+/// pushes a value and cannot fail. `GetGlobal` is not, since the global may be undefined
+fn is_pure_push(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Constant | Opcode::Nil | Opcode::True | Opcode::False
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize(source: &str) -> Chunk {
+        let mut chunk = Chunk::assemble(source).unwrap();
+        chunk.optimize();
+        chunk
+    }
+
+    #[test]
+    fn fuse_comparisons() {
+        let chunk = optimize(
+            ".constants
+                @a
+            .code
+                .line 1
+                GetGlobal 0
+                Nil
+                Equal
+                .line 2
+                Not
+                GetGlobal 0
+                Lesser
+                Not
+                Not
+                Return",
+        );
+
+        assert_eq!(
+            chunk.code(),
+            &[
+                Opcode::GetGlobal.into(),
+                0,
+                Opcode::Nil.into(),
+                Opcode::NotEqual.into(),
+                Opcode::GetGlobal.into(),
+                0,
+                Opcode::Lesser.into(),
+                Opcode::Return.into(),
+            ]
+        );
+        assert_eq!(chunk.lines, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn remove_dead_pushes() {
+        let chunk = optimize(
+            ".constants
+                1
+                @a
+            .code
+                Nil
+                Constant 0
+                Pop
+                Pop
+                GetGlobal 1
+                Pop
+                True
+                Print
+                Return",
+        );
+
+        assert_eq!(
+            chunk.code(),
+            &[
+                Opcode::GetGlobal.into(),
+                1,
+                Opcode::Pop.into(),
+                Opcode::True.into(),
+                Opcode::Print.into(),
+                Opcode::Return.into(),
+            ]
+        );
+        assert_eq!(chunk.lines.len(), chunk.len());
+        assert!(chunk.verify().is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::value::{NativeFnPtr, Value};
+use crate::vm::{RuntimeError, Vm};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the built-in native functions, with their arities
+const NATIVES: [(&str, usize, NativeFnPtr); 15] = [
+    ("clock", 0, clock),
+    ("sqrt", 1, sqrt),
+    ("floor", 1, floor),
+    ("abs", 1, abs),
+    ("min", 2, min),
+    ("max", 2, max),
+    ("pow", 2, pow),
+    ("len", 1, len),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("type", 1, type_of),
+    ("substr", 3, substr),
+    ("indexOf", 2, index_of),
+    ("upper", 1, upper),
+    ("lower", 1, lower),
+];
+
+/// Installs the built-in native functions into the globals of `vm`
+pub fn install(vm: &mut Vm) {
+    for (name, arity, function) in NATIVES {
+        vm.define_native(name, arity, function);
+    }
+}
+
+/// The arity of the built-in function called `name`, if there is one.
+/// Lets tools know the prelude without building a vm.
+pub fn arity(name: &str) -> Option<usize> {
+    NATIVES
+        .iter()
+        .find(|&&(native, _, _)| native == name)
+        .map(|&(_, arity, _)| arity)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    arity(name).is_some()
+}
+
+fn expect_number(name: &str, args: &[Value], index: usize) -> Result<f64, RuntimeError> {
+    match args[index] {
+        Value::Number(n) => Ok(n),
+        ref v => Err(RuntimeError::new(format!(
+            "{}() expects a number as argument {}. Encountered {}",
+            name,
+            index + 1,
+            v
+        ))),
+    }
+}
+
+fn expect_string<'a>(name: &str, args: &'a [Value], index: usize) -> Result<&'a str, RuntimeError> {
+    match args[index] {
+        Value::String(ref s) => Ok(s),
+        ref v => Err(RuntimeError::new(format!(
+            "{}() expects a string as argument {}. Encountered {}",
+            name,
+            index + 1,
+            v
+        ))),
+    }
+}
+
+/// converts a number to a character index, rejecting fractions and negatives
+fn expect_index(name: &str, args: &[Value], index: usize) -> Result<usize, RuntimeError> {
+    let n = expect_number(name, args, index)?;
+    if n < 0.0 || n.fract() != 0.0 {
+        return Err(RuntimeError::new(format!(
+            "{}() expects a non-negative integer as argument {}. Encountered {}",
+            name,
+            index + 1,
+            n
+        )));
+    }
+    Ok(n as usize)
+}
+
+fn string(s: String) -> Value {
+    Value::String(Rc::new(s))
+}
+
+fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RuntimeError::new("System clock is set before the unix epoch."))?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+fn sqrt(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(expect_number("sqrt", args, 0)?.sqrt()))
+}
+
+fn floor(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(expect_number("floor", args, 0)?.floor()))
+}
+
+fn abs(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(expect_number("abs", args, 0)?.abs()))
+}
+
+fn min(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let a = expect_number("min", args, 0)?;
+    let b = expect_number("min", args, 1)?;
+    Ok(Value::Number(a.min(b)))
+}
+
+fn max(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let a = expect_number("max", args, 0)?;
+    let b = expect_number("max", args, 1)?;
+    Ok(Value::Number(a.max(b)))
+}
+
+fn pow(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let base = expect_number("pow", args, 0)?;
+    let exp = expect_number("pow", args, 1)?;
+    Ok(Value::Number(base.powf(exp)))
+}
+
+fn len(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::String(ref s) => Ok(Value::Number(s.chars().count() as f64)),
+        Value::Map(ref m) => Ok(Value::Number(m.borrow().len() as f64)),
+        ref v => Err(RuntimeError::new(format!(
+            "len() expects a string or a map. Encountered {}",
+            v
+        ))),
+    }
+}
+
+fn str(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::String(_) => Ok(args[0].clone()),
+        ref v => Ok(string(v.to_string())),
+    }
+}
+
+/// parses a string into a number, producing nil if it is not numeric
+fn num(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Number(_) => Ok(args[0].clone()),
+        Value::String(ref s) => Ok(s
+            .trim()
+            .parse::<f64>()
+            .map(Value::Number)
+            .unwrap_or(Value::Nil)),
+        ref v => Err(RuntimeError::new(format!(
+            "num() expects a string or a number. Encountered {}",
+            v
+        ))),
+    }
+}
+
+fn type_of(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = match args[0] {
+        Value::Number(_) => "number",
+        Value::Bool(_) => "bool",
+        Value::String(_) => "string",
+        Value::Ident(_) => "ident",
+        Value::Map(_) => "map",
+        Value::Native(_) => "function",
+        Value::Nil => "nil",
+    };
+    Ok(string(name.to_owned()))
+}
+
+/// substr(s, start, length), counted in characters
+fn substr(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let s = expect_string("substr", args, 0)?;
+    let start = expect_index("substr", args, 1)?;
+    let length = expect_index("substr", args, 2)?;
+
+    let count = s.chars().count();
+    // huge arguments saturate to usize::MAX, so the end may overflow
+    if start.checked_add(length).is_none_or(|end| end > count) {
+        return Err(RuntimeError::new(format!(
+            "substr() range {}..{} is out of bounds for a string of length {}.",
+            start,
+            start.saturating_add(length),
+            count
+        )));
+    }
+
+    Ok(string(s.chars().skip(start).take(length).collect()))
+}
+
+/// character index of the first occurrence of needle, or -1
+fn index_of(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let haystack = expect_string("indexOf", args, 0)?;
+    let needle = expect_string("indexOf", args, 1)?;
+
+    let index = match haystack.find(needle) {
+        Some(byte_index) => haystack[..byte_index].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+fn upper(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(string(expect_string("upper", args, 0)?.to_uppercase()))
+}
+
+fn lower(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(string(expect_string("lower", args, 0)?.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &str) -> Value {
+        string(v.to_owned())
+    }
+
+    #[test]
+    fn names() {
+        let vm = Vm::new();
+        for (name, arity, _) in NATIVES {
+            let Some(Value::Native(native)) = vm.get_global(name) else {
+                panic!("'{}' is not installed.", name);
+            };
+            assert_eq!(native.arity, arity);
+        }
+        assert_eq!(arity("substr"), Some(3));
+        assert!(is_builtin("clock"));
+        assert!(!is_builtin("print"));
+    }
+
+    #[test]
+    fn strings() {
+        let mut vm = Vm::without_prelude();
+        assert_eq!(len(&mut vm, &[s("héllo")]), Ok(Value::Number(5f64)));
+        assert_eq!(
+            substr(
+                &mut vm,
+                &[s("héllo"), Value::Number(1f64), Value::Number(3f64)]
+            ),
+            Ok(s("éll"))
+        );
+        assert_eq!(
+            index_of(&mut vm, &[s("héllo"), s("llo")]),
+            Ok(Value::Number(2f64))
+        );
+        assert_eq!(
+            index_of(&mut vm, &[s("héllo"), s("x")]),
+            Ok(Value::Number(-1f64))
+        );
+        assert_eq!(upper(&mut vm, &[s("abc")]), Ok(s("ABC")));
+        assert!(substr(
+            &mut vm,
+            &[s("abc"), Value::Number(2f64), Value::Number(2f64)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn substr_with_huge_arguments() {
+        let mut vm = Vm::without_prelude();
+        let huge = Value::Number(1e300);
+        assert!(substr(&mut vm, &[s("abc"), huge.clone(), huge.clone()]).is_err());
+        assert!(substr(&mut vm, &[s("abc"), Value::Number(1f64), huge]).is_err());
+    }
+
+    #[test]
+    fn conversions() {
+        let mut vm = Vm::without_prelude();
+        assert_eq!(num(&mut vm, &[s(" 4.5 ")]), Ok(Value::Number(4.5)));
+        assert_eq!(num(&mut vm, &[s("four")]), Ok(Value::Nil));
+        assert_eq!(str(&mut vm, &[Value::Number(3f64)]), Ok(s("3")));
+        assert_eq!(type_of(&mut vm, &[Value::Nil]), Ok(s("nil")));
+    }
+
+    #[test]
+    fn type_errors() {
+        let mut vm = Vm::without_prelude();
+        assert!(sqrt(&mut vm, &[s("4")]).is_err());
+        assert!(min(&mut vm, &[Value::Number(1f64), Value::Nil]).is_err());
+        assert!(upper(&mut vm, &[Value::Bool(true)]).is_err());
+    }
+
+    #[test]
+    fn installed() {
+        let mut vm = Vm::new();
+        assert!(matches!(vm.get_global("clock"), Some(Value::Native(_))));
+
+        let bare = Vm::without_prelude();
+        assert!(bare.get_global("clock").is_none());
+        let _ = clock(&mut vm, &[]).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Instruction counts gathered by a vm in profiling mode.
+//!
+//! Every executed instruction is counted, by opcode, source line and offset, so the
+//! profile is exact and the same on every run rather than sampled on a timer.
+//! Lox has no functions of its own yet, so the script is the only frame in the
+//! collapsed stacks, with a frame per line under it.
+
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// instructions executed, indexed by opcode byte
+    opcodes: [u64; 256],
+    lines: BTreeMap<u32, u64>,
+    /// times each instruction ran, indexed by offset into the chunk
+    offsets: Vec<u64>,
+    total: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            opcodes: [0; 256],
+            lines: BTreeMap::new(),
+            offsets: Vec::new(),
+            total: 0,
+        }
+    }
+}
+
+impl Profile {
+    /// counts the instruction at `offset`, which is about to run
+    #[inline]
+    pub fn record(&mut self, chunk: &Chunk, offset: usize) {
+        self.opcodes[chunk.code()[offset] as usize] += 1;
+        *self.lines.entry(chunk.lines[offset]).or_default() += 1;
+        if offset >= self.offsets.len() {
+            self.offsets.resize(offset + 1, 0);
+        }
+        self.offsets[offset] += 1;
+        self.total += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes[u8::from(opcode) as usize]
+    }
+
+    pub fn line_count(&self, line: u32) -> u64 {
+        self.lines.get(&line).copied().unwrap_or(0)
+    }
+
+    /// how many times the instruction at `offset` ran
+    pub fn offset_count(&self, offset: usize) -> u64 {
+        self.offsets.get(offset).copied().unwrap_or(0)
+    }
+
+    /// opcodes that ran, most executed first
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes: Vec<(Opcode, u64)> = (0..=u8::MAX)
+            .filter(|&byte| self.opcodes[byte as usize] > 0)
+            .map(|byte| {
+                let opcode = Opcode::decode(byte).unwrap_or(Opcode::Invalid);
+                (opcode, self.opcodes[byte as usize])
+            })
+            .collect();
+        opcodes.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        opcodes
+    }
+
+    /// lines that ran, most executed first and then in source order
+    pub fn lines(&self) -> Vec<(u32, u64)> {
+        let mut lines: Vec<(u32, u64)> = self.lines.iter().map(|(&l, &n)| (l, n)).collect();
+        lines.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        lines
+    }
+
+    /// A table of the counts by opcode and by line. Lines are shown with
+    /// their text when `source` is given.
+    pub fn report(&self, source: Option<&str>) -> String {
+        let source: Vec<&str> = source.map_or(Vec::new(), |s| s.lines().collect());
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut out = String::new();
+
+        writeln!(out, "{} instructions executed", self.total).unwrap();
+
+        writeln!(out, "\n{:>10} {:>7}  opcode", "count", "%").unwrap();
+        for (opcode, count) in self.opcodes() {
+            writeln!(out, "{:>10} {:>6.2}%  {:?}", count, percent(count), opcode).unwrap();
+        }
+
+        writeln!(out, "\n{:>10} {:>7}  line", "count", "%").unwrap();
+        for (line, count) in self.lines() {
+            // bytecode files may carry any line, including 0
+            let text = (line as usize)
+                .checked_sub(1)
+                .and_then(|i| source.get(i))
+                .map_or("", |text| text.trim());
+            let row = format!(
+                "{:>10} {:>6.2}%  {:>4}  {}",
+                count,
+                percent(count),
+                line,
+                text
+            );
+            writeln!(out, "{}", row.trim_end()).unwrap();
+        }
+        out
+    }
+
+    /// The counts by line as collapsed stacks, one `frame;frame count` per line,
+    /// as taken by flamegraph.pl and inferno. `script` names the outermost frame.
+    pub fn collapsed(&self, script: &str) -> String {
+        let mut out = String::new();
+        for (line, count) in &self.lines {
+            writeln!(out, "{};line {} {}", script, line, count).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(source: &str) -> Profile {
+        let chunk = Chunk::assemble(source).unwrap();
+        let mut profile = Profile::default();
+        let mut offset = 0;
+        while offset < chunk.len() {
+            profile.record(&chunk, offset);
+            offset = chunk.decode(offset).next();
+        }
+        profile
+    }
+
+    #[test]
+    fn counts() {
+        let profile = profile(
+            ".constants
+                1
+            .code
+                .line 1
+                Constant 0
+                Constant 0
+                .line 3
+                Add
+                Print
+                Return",
+        );
+
+        assert_eq!(profile.total(), 5);
+        assert_eq!(profile.opcode_count(Opcode::Constant), 2);
+        assert_eq!(profile.opcode_count(Opcode::Multiply), 0);
+        assert_eq!(profile.line_count(3), 3);
+        assert_eq!(profile.lines(), vec![(3, 3), (1, 2)]);
+        assert_eq!(
+            profile.collapsed("a.lox"),
+            "a.lox;line 1 2\na.lox;line 3 3\n"
+        );
+
+        let report = profile.report(Some("print 1 +\n\n    1;"));
+        assert!(report.starts_with("5 instructions executed\n"));
+        assert!(report.contains("\n         2  40.00%  Constant\n"));
+        assert!(report.contains("\n         3  60.00%     3  1;\n"));
+        assert!(report.ends_with("\n         2  40.00%     1  print 1 +\n"));
+    }
+
+    #[test]
+    fn line_zero() {
+        // bytecode files can carry line 0, which has no source text
+        let profile = profile(".code\n.line 0\nNil\nPrint\nReturn");
+        assert_eq!(profile.line_count(0), 3);
+        assert!(profile
+            .report(Some("print nil;"))
+            .ends_with("\n         3 100.00%     0\n"));
+    }
+}
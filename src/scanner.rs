@@ -3,23 +3,30 @@ use crate::token::{Token, TokenType};
 #[derive(Debug)]
 pub struct Scanner<'src> {
     source: &'src str,
-    // I could replace this vector with a window iterator.
-    // but I'm keeping it for simplicity's sake,
-    // since chars() does not provide a windows() function
-    source_vec: Vec<char>,
+    // byte offsets into the source
     start: usize,
     current: usize,
     line: u32,
+    keep_comments: bool,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
         Scanner {
             source,
-            source_vec: source.chars().collect::<Vec<char>>(),
             start: 0,
             current: 0,
             line: 1,
+            keep_comments: false,
+        }
+    }
+
+    /// Creates a scanner that returns `//` comments as `Comment` tokens
+    /// instead of skipping them, for tools that work on the source text
+    pub fn with_comments(source: &'src str) -> Self {
+        Scanner {
+            keep_comments: true,
+            ..Self::new(source)
         }
     }
 
@@ -32,10 +39,14 @@ impl<'src> Scanner<'src> {
                 token_type: Eof,
                 lexeme: "".to_owned(),
                 line: self.line,
+                start: self.source.len(),
             };
         }
 
         self.start = self.current;
+        if self.keep_comments && self.peek() == Some('/') && self.peek_next() == Some('/') {
+            return self.comment();
+        }
         let c = self.advance();
 
         let mut eq_lookahead = |eq, ne| {
@@ -58,19 +69,55 @@ impl<'src> Scanner<'src> {
             ')' => self.make_token(RightParen),
             '{' => self.make_token(LeftBrace),
             '}' => self.make_token(RightBrace),
+            '[' => self.make_token(LeftBracket),
+            ']' => self.make_token(RightBracket),
+            ':' => self.make_token(Colon),
             ',' => self.make_token(Comma),
             '.' => self.make_token(Dot),
             '-' => self.make_token(Minus),
             '+' => self.make_token(Plus),
+            '%' => self.make_token(Percent),
             ';' => self.make_token(Semicolon),
-            '*' => self.make_token(Star),
+            '*' => {
+                if self.consume_eq('*') {
+                    self.make_token(StarStar)
+                } else {
+                    self.make_token(Star)
+                }
+            }
+            '~' => {
+                if self.consume_eq('/') {
+                    self.make_token(TildeSlash)
+                } else {
+                    self.make_token(Tilde)
+                }
+            }
+            '&' => self.make_token(Ampersand),
+            '|' => self.make_token(Pipe),
+            '^' => self.make_token(Caret),
             '/' => self.make_token(Slash),
             '!' => eq_lookahead(BangEqual, Bang),
             '=' => eq_lookahead(EqualEqual, Equal),
-            '>' => eq_lookahead(GreaterEqual, Greater),
-            '<' => eq_lookahead(LesserEqual, Lesser),
+            '>' => {
+                if self.consume_eq('>') {
+                    self.make_token(GreaterGreater)
+                } else if self.consume_eq('=') {
+                    self.make_token(GreaterEqual)
+                } else {
+                    self.make_token(Greater)
+                }
+            }
+            '<' => {
+                if self.consume_eq('<') {
+                    self.make_token(LesserLesser)
+                } else if self.consume_eq('=') {
+                    self.make_token(LesserEqual)
+                } else {
+                    self.make_token(Lesser)
+                }
+            }
             '"' => self.string(),
-            d if d.is_digit(10) => self.number(),
+            d if d.is_ascii_digit() => self.number(),
             a if a.is_alphabetic() => self.ident_and_keyword(),
             _ => self.error_token("Invalid token."),
         }
@@ -82,8 +129,8 @@ impl<'src> Scanner<'src> {
 
     fn advance(&mut self) -> Option<char> {
         let c = self.peek();
-        if c.is_some() {
-            self.current += 1;
+        if let Some(c) = c {
+            self.current += c.len_utf8();
         }
         c
     }
@@ -102,19 +149,11 @@ impl<'src> Scanner<'src> {
     }
 
     fn peek(&self) -> Option<char> {
-        if self.current < self.source_vec.len() {
-            Some(self.source_vec[self.current])
-        } else {
-            None
-        }
+        self.source[self.current..].chars().next()
     }
 
     fn peek_next(&self) -> Option<char> {
-        if self.is_at_end() {
-            None
-        } else {
-            Some(self.source_vec[self.current + 1])
-        }
+        self.source[self.current..].chars().nth(1)
     }
 
     fn skip_whitespace(&mut self) {
@@ -127,7 +166,7 @@ impl<'src> Scanner<'src> {
                     self.advance();
                     self.line += 1;
                 }
-                '/' => {
+                '/' if !self.keep_comments => {
                     if let Some('/') = self.peek_next() {
                         while let Some(c) = self.peek() {
                             if c == '\n' {
@@ -144,6 +183,16 @@ impl<'src> Scanner<'src> {
         }
     }
 
+    fn comment(&mut self) -> Token {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.advance();
+        }
+        self.make_token(TokenType::Comment)
+    }
+
     fn string(&mut self) -> Token {
         loop {
             match self.peek() {
@@ -159,13 +208,12 @@ impl<'src> Scanner<'src> {
         }
 
         self.advance();
-        let token = self.make_token(TokenType::String);
-        token
+        self.make_token(TokenType::String)
     }
 
     fn number(&mut self) -> Token {
         while let Some(c) = self.peek() {
-            if c.is_digit(10) {
+            if c.is_ascii_digit() {
                 self.advance();
             } else {
                 break;
@@ -175,7 +223,7 @@ impl<'src> Scanner<'src> {
         if let Some('.') = self.peek() {
             self.advance();
             while let Some(c) = self.peek() {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.advance();
                 } else {
                     break;
@@ -187,12 +235,13 @@ impl<'src> Scanner<'src> {
             token_type: TokenType::Number,
             lexeme: self.source[self.start..self.current].to_owned(),
             line: self.line,
+            start: self.start,
         }
     }
 
     fn ident_and_keyword(&mut self) -> Token {
-        let is_alpha = |c| ('a' <= c && c <= 'z') || ('A' <= c && c <= 'Z') || c == '_';
-        let is_alphanum = |c| ('0' <= c && c <= '9') || is_alpha(c);
+        let is_alpha = |c: char| c.is_ascii_alphabetic() || c == '_';
+        let is_alphanum = |c: char| c.is_ascii_digit() || is_alpha(c);
 
         while let Some(c) = self.peek() {
             if is_alphanum(c) {
@@ -269,6 +318,7 @@ impl<'src> Scanner<'src> {
             token_type,
             lexeme: self.source[self.start..self.current].to_owned(),
             line: self.line,
+            start: self.start,
         }
     }
 
@@ -277,6 +327,7 @@ impl<'src> Scanner<'src> {
             token_type: TokenType::Error,
             lexeme: message.to_owned(),
             line: self.line,
+            start: self.start,
         }
     }
 }
@@ -361,6 +412,25 @@ mod tests {
         test_code(code, expected);
     }
 
+    #[test]
+    fn arithmetic_operators() {
+        let code = "2 ** 3 % 4 ~/ 5 * 6";
+        let expected = vec![
+            Number, StarStar, Number, Percent, Number, TildeSlash, Number, Star, Number,
+        ];
+        test_code(code, expected);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let code = "~a & b | c ^ d << 1 >> 2 <= 3";
+        let expected = vec![
+            Tilde, Identifier, Ampersand, Identifier, Pipe, Identifier, Caret, Identifier,
+            LesserLesser, Number, GreaterGreater, Number, LesserEqual, Number,
+        ];
+        test_code(code, expected);
+    }
+
     #[test]
     fn symbols() {
         let code = "} { ) ( . , ;";
@@ -370,6 +440,16 @@ mod tests {
         test_code(code, expected);
     }
 
+    #[test]
+    fn map_literal() {
+        let code = r#"{"a": 1, "b": 2}[ "a" ]"#;
+        let expected = vec![
+            LeftBrace, String, Colon, Number, Comma, String, Colon, Number, RightBrace,
+            LeftBracket, String, RightBracket,
+        ];
+        test_code(code, expected);
+    }
+
     #[test]
     fn literals() {
         let code = "\"Hello World\" 3.1415";
@@ -419,4 +499,33 @@ mod tests {
         let expected = vec![Var, Identifier, Equal, String, Semicolon];
         test_code(code, expected);
     }
+
+    #[test]
+    fn unicode() {
+        // offsets count bytes, so lexemes after non-ascii text stay intact
+        let tokens = scan_tokens("// ünïcode\nprint \"héllo\" + x;");
+        compare(tokens.clone(), vec![Print, String, Plus, Identifier, Semicolon]);
+        assert_eq!(tokens[1].lexeme, "\"héllo\"");
+        assert_eq!(tokens[3].lexeme, "x");
+        assert_eq!(tokens[3].start, 30);
+        assert_eq!(tokens[3].line, 2);
+    }
+
+    #[test]
+    fn comments() {
+        let code = "var a; // trailing\n// own line\nprint a / 2;";
+        let expected = vec![Var, Identifier, Semicolon, Print, Identifier, Slash, Number, Semicolon];
+        compare(scan_tokens(code), expected);
+
+        let mut sc = Scanner::with_comments(code);
+        let mut tokens = Vec::new();
+        while !sc.is_at_end() {
+            tokens.push(sc.scan_token());
+        }
+        let comments: Vec<_> = tokens.iter().filter(|t| t.token_type == Comment).collect();
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0].lexeme, "// trailing");
+        assert_eq!((comments[1].lexeme.as_str(), comments[1].line), ("// own line", 2));
+        assert_eq!(tokens.len(), 10);
+    }
 }
@@ -0,0 +1,293 @@
+//! The `.loxc` bytecode file format.
+//!
+//! All integers are little endian.
+//!
+//! ```text
+//! magic      b"LOXC"
+//! version    u16
+//! code       u32 length, then the bytes
+//! lines      u32 per byte of code
+//! constants  u32 count, then per constant a u8 tag and its payload:
+//!            0 number  f64
+//!            1 bool    u8
+//!            2 nil
+//!            3 string  u32 length, utf-8 bytes
+//!            4 ident   u32 length, utf-8 bytes
+//! checksum   u32 CRC-32 of everything before it
+//! ```
+//!
+//! Lox has no function values yet. Once it does, their chunks will be
+//! nested into the constant pool under a new tag and a new version.
+
+use crate::chunk::Chunk;
+use crate::value::Value;
+use crate::verifier::VerifyError;
+use std::fmt::Display;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 1;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NIL: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_IDENT: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// only literal constants can be written out
+    UnsupportedConstant(String),
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// the file ends in the middle of a section
+    Truncated,
+    InvalidConstantTag(u8),
+    InvalidUtf8,
+    TooManyConstants(usize),
+    /// bytes are left over after the constant pool
+    TrailingBytes,
+    /// the decoded chunk failed verification
+    Verify(VerifyError),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FormatError::*;
+
+        match *self {
+            UnsupportedConstant(ref v) => write!(f, "Constant {} cannot be serialized.", v),
+            BadMagic => write!(f, "Not a loxc file."),
+            UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported loxc version {}. Expected version {}.",
+                v, VERSION
+            ),
+            ChecksumMismatch { expected, found } => write!(
+                f,
+                "Checksum mismatch: expected {:08x}, found {:08x}.",
+                expected, found
+            ),
+            Truncated => write!(f, "Unexpected end of file."),
+            InvalidConstantTag(tag) => write!(f, "Invalid constant tag {}.", tag),
+            InvalidUtf8 => write!(f, "String constant is not valid utf-8."),
+            TooManyConstants(n) => write!(f, "Too many constants ({}) in one chunk.", n),
+            TrailingBytes => write!(f, "Unexpected bytes after the constant pool."),
+            Verify(ref e) => write!(f, "Invalid bytecode: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl Chunk {
+    /// Encodes the chunk in the `.loxc` format
+    pub fn to_bytes(&self) -> Result<Vec<u8>, FormatError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        out.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.code());
+        for line in &self.lines {
+            out.extend_from_slice(&line.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.constants().len() as u32).to_le_bytes());
+        for constant in self.constants() {
+            match *constant {
+                Value::Number(n) => {
+                    out.push(TAG_NUMBER);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Bool(b) => {
+                    out.push(TAG_BOOL);
+                    out.push(b as u8);
+                }
+                Value::Nil => out.push(TAG_NIL),
+                Value::String(ref s) => write_str(&mut out, TAG_STRING, s),
+                Value::Ident(ref s) => write_str(&mut out, TAG_IDENT, s),
+                Value::Map(_) | Value::Native(_) => {
+                    return Err(FormatError::UnsupportedConstant(constant.to_string()))
+                }
+            }
+        }
+
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        Ok(out)
+    }
+
+    /// Decodes and verifies a chunk in the `.loxc` format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, FormatError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        if bytes.len() < MAGIC.len() + 2 + 4 {
+            return Err(FormatError::Truncated);
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut reader = Reader { bytes: body, pos: MAGIC.len() };
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+
+        let expected = crc32(body);
+        let found = u32::from_le_bytes(checksum.try_into().unwrap());
+        if expected != found {
+            return Err(FormatError::ChecksumMismatch { expected, found });
+        }
+
+        let code_len = reader.u32()? as usize;
+        let code = reader.take(code_len)?.to_vec();
+        let lines = (0..code_len)
+            .map(|_| reader.u32())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = reader.u32()? as usize;
+        if count > u8::MAX as usize + 1 {
+            return Err(FormatError::TooManyConstants(count));
+        }
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            let value = match reader.u8()? {
+                TAG_NUMBER => Value::Number(f64::from_le_bytes(
+                    reader.take(8)?.try_into().unwrap(),
+                )),
+                TAG_BOOL => Value::Bool(reader.u8()? != 0),
+                TAG_NIL => Value::Nil,
+                TAG_STRING => Value::String(Rc::new(reader.string()?)),
+                TAG_IDENT => Value::Ident(Rc::new(reader.string()?)),
+                tag => return Err(FormatError::InvalidConstantTag(tag)),
+            };
+            values.push(value);
+        }
+
+        if reader.pos != body.len() {
+            return Err(FormatError::TrailingBytes);
+        }
+
+        let chunk = Chunk::from_parts(code, lines, values);
+        chunk.verify().map_err(FormatError::Verify)?;
+        Ok(chunk)
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, tag: u8, s: &str) {
+    out.push(tag);
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        let end = self.pos.checked_add(n).ok_or(FormatError::Truncated)?;
+        let slice = self.bytes.get(self.pos..end).ok_or(FormatError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, FormatError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| FormatError::InvalidUtf8)
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bit by bit
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn compile(source: &str) -> Chunk {
+        let mut compiler = Compiler::new(source);
+        assert!(!compiler.compile(Chunk::new()));
+        compiler.take_chunk()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = compile("var a = \"hello\";\nprint a + 1.5;\nprint nil == {true: 2}[false];");
+        let bytes = chunk.to_bytes().unwrap();
+        let loaded = Chunk::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.code(), chunk.code());
+        assert_eq!(loaded.lines, chunk.lines);
+        assert_eq!(loaded.constants(), chunk.constants());
+        assert_eq!(loaded.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn corrupted() {
+        let bytes = compile("print 1 + 2;").to_bytes().unwrap();
+
+        assert_eq!(Chunk::from_bytes(b"LOX").err(), Some(FormatError::BadMagic));
+
+        let mut flipped = bytes.clone();
+        flipped[10] ^= 0xff;
+        assert!(matches!(
+            Chunk::from_bytes(&flipped),
+            Err(FormatError::ChecksumMismatch { .. })
+        ));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(
+            Chunk::from_bytes(&version).err(),
+            Some(FormatError::UnsupportedVersion(9))
+        );
+
+        let truncated = &bytes[..bytes.len() - 6];
+        assert!(Chunk::from_bytes(truncated).is_err());
+    }
+
+    #[test]
+    fn unverifiable() {
+        // a valid file whose code pops from an empty stack
+        let chunk = Chunk::from_parts(vec![3, 0], vec![1, 1], Vec::new());
+        let bytes = chunk.to_bytes().unwrap();
+        assert!(matches!(
+            Chunk::from_bytes(&bytes),
+            Err(FormatError::Verify(VerifyError::StackUnderflow { .. }))
+        ));
+    }
+}
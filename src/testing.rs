@@ -0,0 +1,289 @@
+//! Checks Lox scripts against the annotations in their comments, behind
+//! `rustox test`. The annotations follow the Crafting Interpreters test suite:
+//!
+//! ```text
+//! print 1 + 2; // expect: 3
+//! print nowhere; // expect runtime error: Undefined variable 'nowhere'.
+//! print 1 +; // Error at ';': Expect expression.
+//! // [line 3] Error at end: Expected ';' after print statement.
+//! ```
+//!
+//! `// [c line N]` annotations are accepted as well, and `// [java line N]` ones
+//! are ignored.
+
+use crate::chunk::Chunk;
+use crate::compiler::Compiler;
+use crate::profile::Profile;
+use crate::vm::{InterpretResult, Vm};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// exit codes from sysexits.h, as the cli returns them
+pub const EX_DATAERR: i32 = 65;
+pub const EX_SOFTWARE: i32 = 70;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Expectations {
+    /// each printed line, with the line of its annotation
+    pub output: Vec<(usize, String)>,
+    /// compile errors, as reported
+    pub compile_errors: Vec<String>,
+    pub runtime_error: Option<(usize, String)>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Expectations {
+        let mut expect = Expectations::default();
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let comment = match line.find("//") {
+                Some(start) => line[start + 2..].trim(),
+                None => continue,
+            };
+
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expect.output.push((line_number, output.to_owned()));
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expect.runtime_error = Some((line_number, message.to_owned()));
+            } else if comment.starts_with("Error") {
+                expect
+                    .compile_errors
+                    .push(format!("[line {}] {}", line_number, comment));
+            } else if let Some(rest) = comment
+                .strip_prefix("[line ")
+                .or_else(|| comment.strip_prefix("[c line "))
+            {
+                if let Some((n, error)) = rest.split_once("] ") {
+                    expect
+                        .compile_errors
+                        .push(format!("[line {}] {}", n, error));
+                }
+            }
+        }
+
+        expect
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            EX_DATAERR
+        } else if self.runtime_error.is_some() {
+            EX_SOFTWARE
+        } else {
+            0
+        }
+    }
+
+    /// Compares how a script ran with the expectations, describing every mismatch
+    pub fn check(&self, outcome: &Outcome) -> Vec<String> {
+        let mut failures = Vec::new();
+
+        for (i, (line, expected)) in self.output.iter().enumerate() {
+            match outcome.output.get(i) {
+                Some(actual) if actual == expected => (),
+                Some(actual) => failures.push(format!(
+                    "line {}: expected output '{}', got '{}'",
+                    line, expected, actual
+                )),
+                None => failures.push(format!(
+                    "line {}: missing expected output '{}'",
+                    line, expected
+                )),
+            }
+        }
+        for extra in outcome.output.iter().skip(self.output.len()) {
+            failures.push(format!("unexpected output '{}'", extra));
+        }
+
+        let errors = &outcome.errors;
+        if !self.compile_errors.is_empty() {
+            if *errors != self.compile_errors {
+                failures.push(format!(
+                    "expected compile errors {:?}, got {:?}",
+                    self.compile_errors, errors
+                ));
+            }
+        } else if let Some((line, ref message)) = self.runtime_error {
+            let trace = format!("[line {}] in script", line);
+            if errors.first() != Some(message) || errors.get(1) != Some(&trace) {
+                failures.push(format!(
+                    "line {}: expected runtime error '{}', got {:?}",
+                    line, message, errors
+                ));
+            }
+        } else if !errors.is_empty() {
+            failures.push(format!("unexpected errors {:?}", errors));
+        }
+
+        if outcome.exit_code != Some(self.exit_code()) {
+            failures.push(format!(
+                "expected exit code {}, got {:?}",
+                self.exit_code(),
+                outcome.exit_code
+            ));
+        }
+
+        failures
+    }
+}
+
+/// How a script ran
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// the lines printed
+    pub output: Vec<String>,
+    /// the lines of compile errors or the runtime error report
+    pub errors: Vec<String>,
+    /// the exit code the cli would return, if it exited normally
+    pub exit_code: Option<i32>,
+}
+
+/// A script run in-process, along with what it compiled to and the
+/// instructions it executed, for coverage
+pub struct Run {
+    pub outcome: Outcome,
+    /// `None` if the script did not compile
+    pub chunk: Option<Chunk>,
+    pub profile: Option<Profile>,
+}
+
+/// Compiles and runs a script in-process with the same compiler as `rustox run`,
+/// capturing its output and errors
+pub fn run(source: &str) -> Run {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let errors = Rc::new(RefCell::new(Vec::new()));
+
+    let mut compiler = Compiler::new(source);
+    compiler.set_print_errors(false);
+    if compiler.compile(Chunk::new()) {
+        let outcome = Outcome {
+            output: Vec::new(),
+            errors: compiler.errors().iter().map(|e| e.to_string()).collect(),
+            exit_code: Some(EX_DATAERR),
+        };
+        return Run {
+            outcome,
+            chunk: None,
+            profile: None,
+        };
+    }
+    let chunk = compiler.take_chunk();
+
+    let mut vm = Vm::new();
+    let sink = output.clone();
+    vm.set_output_fn(move |line| sink.borrow_mut().push(line.to_owned()));
+    let sink = errors.clone();
+    vm.set_error_fn(move |report| sink.borrow_mut().extend(report.lines().map(str::to_owned)));
+    vm.set_profiling(true);
+
+    let exit_code = match vm.interpret(chunk) {
+        InterpretResult::Ok => 0,
+        InterpretResult::CompileError => EX_DATAERR,
+        InterpretResult::RuntimeError => EX_SOFTWARE,
+    };
+    let outcome = Outcome {
+        output: output.take(),
+        errors: errors.take(),
+        exit_code: Some(exit_code),
+    };
+    Run {
+        outcome,
+        chunk: vm.take_chunk(),
+        profile: vm.take_profile(),
+    }
+}
+
+/// Collects the `.lox` scripts in a directory and its subdirectories, or
+/// `path` itself if it is a file
+pub fn find_scripts(path: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        found.push(path.to_owned());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_scripts(&path, found)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotations() {
+        let expect = Expectations::parse(
+            "print 1; // expect: 1\n\
+             print a; // expect runtime error: Undefined variable 'a'.\n\
+             print 1 +; // Error at ';': Expect expression.\n\
+             // [c line 5] Error at end: Expect ';'.\n\
+             // [java line 5] Error at end: Expect ';'.",
+        );
+
+        assert_eq!(expect.output, vec![(1, "1".to_owned())]);
+        assert_eq!(
+            expect.runtime_error,
+            Some((2, "Undefined variable 'a'.".to_owned()))
+        );
+        assert_eq!(
+            expect.compile_errors,
+            vec![
+                "[line 3] Error at ';': Expect expression.".to_owned(),
+                "[line 5] Error at end: Expect ';'.".to_owned(),
+            ]
+        );
+        assert_eq!(expect.exit_code(), EX_DATAERR);
+    }
+
+    #[test]
+    fn check_outcomes() {
+        let source =
+            "print 1; // expect: 1\nprint a; // expect runtime error: Undefined variable 'a'.";
+        let expect = Expectations::parse(source);
+        assert_eq!(expect.check(&run(source).outcome), Vec::<String>::new());
+
+        let failures = expect.check(&run("print 2;\nprint 3;").outcome);
+        assert_eq!(
+            failures,
+            vec![
+                "line 1: expected output '1', got '2'",
+                "unexpected output '3'",
+                "line 2: expected runtime error 'Undefined variable 'a'.', got []",
+                "expected exit code 70, got Some(0)",
+            ]
+        );
+
+        let run = run("print 1 +;");
+        assert!(run.chunk.is_none());
+        assert_eq!(
+            run.outcome.errors,
+            vec!["[line 1] Error at ';': Expect expression."]
+        );
+    }
+
+    #[test]
+    fn test_scripts_pass_in_process() {
+        let mut paths = Vec::new();
+        find_scripts(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"),
+            &mut paths,
+        )
+        .unwrap();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let source = fs::read_to_string(&path).unwrap();
+            let failures = Expectations::parse(&source).check(&run(&source).outcome);
+            assert!(failures.is_empty(), "{}: {:?}", path.display(), failures);
+        }
+    }
+}
@@ -3,14 +3,18 @@ pub enum TokenType {
     // Single-Character Tokens
     LeftParen, RightParen,
     LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus,
-    Semicolon, Slash, Star,
+    LeftBracket, RightBracket,
+    Colon, Comma, Dot, Minus, Plus,
+    Percent, Semicolon, Slash, Star,
+    Ampersand, Pipe, Caret, Tilde,
 
     // One or two character tokens.
     Bang, BangEqual,
     Equal, EqualEqual,
     Greater, GreaterEqual,
     Lesser, LesserEqual,
+    StarStar, TildeSlash,
+    LesserLesser, GreaterGreater,
 
     // Literals.
     Identifier, String, Number,
@@ -21,6 +25,9 @@ pub enum TokenType {
     Print, Return, Super, This,
     True, Var, While,
 
+    // only produced by `Scanner::with_comments`
+    Comment,
+
     Error,
 
     Eof,
@@ -31,4 +38,6 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: u32,
+    /// byte offset of the lexeme in the source
+    pub start: usize,
 }
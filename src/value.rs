@@ -1,25 +1,146 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     fmt::Display,
-    ops::{Add, Div, Mul, Neg, Not, Sub},
+    hash::{Hash, Hasher},
+    ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub},
 };
 
 use std::rc::Rc;
 
+use crate::vm::{RuntimeError, Vm};
+
+/// A hash map that keeps its entries in insertion order,
+/// so that printing a map shows the same text on every run
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    /// the position of each key in `entries`
+    indices: HashMap<Value, usize>,
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Map {
+        Map {
+            entries: Vec::with_capacity(capacity),
+            indices: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.indices.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Sets the value of `key`. A key that is already present keeps its position.
+    pub fn insert(&mut self, key: Value, value: Value) -> Option<Value> {
+        match self.indices.get(&key) {
+            Some(&i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the entries in the order their keys were first inserted
+    pub fn iter(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+pub type NativeFnPtr = fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError>;
+
+/// The representation of values on the vm stack.
+/// With the `nan-boxing` feature, values are packed into 64 bits.
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+
+#[cfg(feature = "nan-boxing")]
+pub type Slot = crate::nanbox::NanBox;
+
+/// Conversions between a `Value` and its representation on the vm stack
+pub trait StackSlot: Clone + std::fmt::Debug + PartialEq<Value> {
+    fn from_value(value: Value) -> Self;
+    fn into_value(self) -> Value;
+    fn to_value(&self) -> Value;
+    fn from_number(n: f64) -> Self;
+    fn as_number(&self) -> Option<f64>;
+}
+
+impl StackSlot for Value {
+    #[inline]
+    fn from_value(value: Value) -> Self {
+        value
+    }
+
+    #[inline]
+    fn into_value(self) -> Value {
+        self
+    }
+
+    #[inline]
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+
+    #[inline]
+    fn from_number(n: f64) -> Self {
+        Value::Number(n)
+    }
+
+    #[inline]
+    fn as_number(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+/// A function implemented by the host, registered with `Vm::define_native`
+#[derive(Debug)]
+pub struct NativeFn {
+    pub name: Rc<String>,
+    pub arity: usize,
+    pub function: NativeFnPtr,
+}
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Number(f64),
     Bool(bool),
     String(Rc<String>),
     Ident(Rc<String>),
+    Map(Rc<RefCell<Map>>),
+    Native(Rc<NativeFn>),
     Nil,
 }
 
 impl Value {
     pub fn truthy(&self) -> bool {
+        !matches!(*self, Self::Nil | Self::Bool(false))
+    }
+
+    /// whether this value may be used as a map key.
+    /// NaN is rejected since it is never equal to itself.
+    pub fn hashable(&self) -> bool {
         match *self {
-            Self::Nil => false,
-            Self::Bool(false) => false,
-            _ => true,
+            Self::Number(n) => !n.is_nan(),
+            Self::Bool(_) | Self::String(_) | Self::Nil => true,
+            Self::Ident(_) | Self::Map(_) | Self::Native(_) => false,
         }
     }
 }
@@ -33,68 +154,187 @@ impl Display for Value {
             Self::Bool(true) => write!(f, "True"),
             Self::Bool(false) => write!(f, "False"),
             Self::Ident(ref ident) => write!(f, "@{}", ident),
+            Self::Map(ref map) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Self::Native(ref native) => write!(f, "<native fn {}>", native.name),
         }
     }
 }
 
+/// the result of an arithmetic operation on values
+pub type ValueResult = Result<Value, RuntimeError>;
+
+fn expected_number(l: Value, r: Value) -> RuntimeError {
+    RuntimeError::new(format!("Expected Number. Encountered {} and {}", l, r))
+}
+
 impl Neg for Value {
-    type Output = Self;
+    type Output = ValueResult;
 
     fn neg(self) -> Self::Output {
         match self {
-            Self::Number(f) => Self::Number(-f),
-            v => panic!("Expected Number. Encountered {}", v),
+            Self::Number(f) => Ok(Self::Number(-f)),
+            v => Err(RuntimeError::new(format!("Expected Number. Encountered {}", v))),
         }
     }
 }
 
 impl Add for Value {
-    type Output = Self;
+    type Output = ValueResult;
 
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Self::Number(l), Self::Number(r)) => Self::Number(l + r),
-            (Self::Number(l), Self::String(ref r)) => Self::String(Rc::new(format!("{}{}", l, r))),
-            (Self::String(ref l), Self::Number(r)) => Self::String(Rc::new(format!("{}{}", l, r))),
-            (Self::String(ref l), Self::String(ref r)) => Self::String(Rc::new(format!("{}{}", l, r))),
-            (l, r) => panic!("Expected Number. Encountered {} and {}", l, r),
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l + r)),
+            (Self::Number(l), Self::String(ref r)) => Ok(Self::String(Rc::new(format!("{}{}", l, r)))),
+            (Self::String(ref l), Self::Number(r)) => Ok(Self::String(Rc::new(format!("{}{}", l, r)))),
+            (Self::String(ref l), Self::String(ref r)) => Ok(Self::String(Rc::new(format!("{}{}", l, r)))),
+            (l, r) => Err(expected_number(l, r)),
         }
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = ValueResult;
 
     fn sub(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Self::Number(l), Self::Number(r)) => Self::Number(l - r),
-            (l, r) => panic!("Expected Number. Encountered {} and {}", l, r),
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l - r)),
+            (l, r) => Err(expected_number(l, r)),
         }
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = ValueResult;
 
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Self::Number(l), Self::Number(r)) => Self::Number(l * r),
-            (l, r) => panic!("Expected Number. Encountered {} and {}", l, r),
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l * r)),
+            (l, r) => Err(expected_number(l, r)),
         }
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = ValueResult;
 
     fn div(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Self::Number(l), Self::Number(r)) => Self::Number(l / r),
-            (l, r) => panic!("Expected Number. Encountered {} and {}", l, r),
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l / r)),
+            (l, r) => Err(expected_number(l, r)),
+        }
+    }
+}
+
+/// floored modulo: the result takes the sign of the divisor,
+/// so that `a == b * (a ~/ b) + a % b` holds
+impl Rem for Value {
+    type Output = ValueResult;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l - r * (l / r).floor())),
+            (l, r) => Err(expected_number(l, r)),
+        }
+    }
+}
+
+impl Value {
+    pub fn pow(self, rhs: Self) -> ValueResult {
+        match (self, rhs) {
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number(l.powf(r))),
+            (l, r) => Err(expected_number(l, r)),
+        }
+    }
+
+    pub fn floor_div(self, rhs: Self) -> ValueResult {
+        match (self, rhs) {
+            (Self::Number(l), Self::Number(r)) => Ok(Self::Number((l / r).floor())),
+            (l, r) => Err(expected_number(l, r)),
         }
     }
 }
 
+impl Value {
+    /// bitwise operands must be exact integers within the range of an i64
+    fn as_integer(&self) -> Result<i64, RuntimeError> {
+        match *self {
+            Self::Number(n)
+                if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 =>
+            {
+                Ok(n as i64)
+            }
+            ref v => Err(RuntimeError::new(format!(
+                "Expected integer. Encountered {}",
+                v
+            ))),
+        }
+    }
+
+    /// shift amounts must lie in 0..64
+    fn as_shift(&self) -> Result<u32, RuntimeError> {
+        let n = self.as_integer()?;
+        if (0..64).contains(&n) {
+            Ok(n as u32)
+        } else {
+            Err(RuntimeError::new(format!("Shift amount {} is out of range.", n)))
+        }
+    }
+
+    pub fn bit_not(self) -> ValueResult {
+        Ok(Self::Number(!self.as_integer()? as f64))
+    }
+}
+
+impl BitAnd for Value {
+    type Output = ValueResult;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Ok(Self::Number((self.as_integer()? & rhs.as_integer()?) as f64))
+    }
+}
+
+impl BitOr for Value {
+    type Output = ValueResult;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Ok(Self::Number((self.as_integer()? | rhs.as_integer()?) as f64))
+    }
+}
+
+impl BitXor for Value {
+    type Output = ValueResult;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        Ok(Self::Number((self.as_integer()? ^ rhs.as_integer()?) as f64))
+    }
+}
+
+impl Shl for Value {
+    type Output = ValueResult;
+
+    fn shl(self, rhs: Self) -> Self::Output {
+        Ok(Self::Number((self.as_integer()? << rhs.as_shift()?) as f64))
+    }
+}
+
+/// arithmetic shift, preserving the sign of the left operand
+impl Shr for Value {
+    type Output = ValueResult;
+
+    fn shr(self, rhs: Self) -> Self::Output {
+        Ok(Self::Number((self.as_integer()? >> rhs.as_shift()?) as f64))
+    }
+}
+
 impl Not for Value {
     type Output = Self;
 
@@ -106,17 +346,47 @@ impl Not for Value {
     }
 }
 
+/// Strings and identifiers are equal when their contents are, so `"a" == "a"`
+/// holds in Lox and equal strings find the same map entry.
+/// Maps and natives are only equal to themselves.
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Number(l), Self::Number(r)) => l == r,
             (Self::Bool(l), Self::Bool(r)) => l == r,
+            (Self::String(l), Self::String(r)) => l == r,
+            (Self::Ident(l), Self::Ident(r)) => l == r,
+            (Self::Map(l), Self::Map(r)) => Rc::ptr_eq(l, r),
+            (Self::Native(l), Self::Native(r)) => Rc::ptr_eq(l, r),
             (Self::Nil, Self::Nil) => true,
             _ => false,
         }
     }
 }
 
+// NaN is the only value that breaks reflexivity,
+// and it is refused as a map key by `Value::hashable`.
+impl Eq for Value {}
+
+/// Hashing agrees with `PartialEq`: numbers hash by their bits
+/// (with -0.0 folded into 0.0), strings by content, maps and natives by identity.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Number(n) => {
+                let n = if *n == 0.0 { 0.0 } else { *n };
+                n.to_bits().hash(state);
+            }
+            Self::Bool(b) => b.hash(state),
+            Self::String(s) | Self::Ident(s) => s.hash(state),
+            Self::Map(m) => Rc::as_ptr(m).hash(state),
+            Self::Native(n) => Rc::as_ptr(n).hash(state),
+            Self::Nil => (),
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
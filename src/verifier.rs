@@ -0,0 +1,258 @@
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+use crate::value::Value;
+use std::fmt::Display;
+
+/// Reasons a chunk is rejected by `Chunk::verify`.
+/// Offsets point at the first byte of the offending instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// the line table does not have one entry per byte of code
+    LineTableMismatch { code: usize, lines: usize },
+    InvalidOpcode { offset: usize, byte: u8 },
+    /// the operand bytes of the instruction run past the end of the code
+    TruncatedInstruction { offset: usize, opcode: Opcode },
+    ConstantOutOfBounds { offset: usize, index: u8 },
+    /// a global instruction whose constant is not an identifier
+    InvalidConstant { offset: usize, index: u8 },
+    StackUnderflow { offset: usize, opcode: Opcode },
+    /// execution can run off the end of the code
+    MissingReturn,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use VerifyError::*;
+
+        match *self {
+            LineTableMismatch { code, lines } => write!(
+                f,
+                "Line table has {} entries for {} bytes of code.",
+                lines, code
+            ),
+            InvalidOpcode { offset, byte } => {
+                write!(f, "[{:04}] Invalid instruction byte {}.", offset, byte)
+            }
+            TruncatedInstruction { offset, opcode } => {
+                write!(f, "[{:04}] {:?} is missing its operand.", offset, opcode)
+            }
+            ConstantOutOfBounds { offset, index } => {
+                write!(f, "[{:04}] Constant {} is out of bounds.", offset, index)
+            }
+            InvalidConstant { offset, index } => {
+                write!(f, "[{:04}] Constant {} is not an identifier.", offset, index)
+            }
+            StackUnderflow { offset, opcode } => {
+                write!(f, "[{:04}] {:?} pops from an empty stack.", offset, opcode)
+            }
+            MissingReturn => write!(f, "Chunk does not end with a return."),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl Chunk {
+    /// Checks that the chunk can be run by the vm without crashing it:
+    /// every opcode is valid, operands are in bounds, no instruction pops
+    /// more values than the stack statically holds, and execution ends in a `Return`.
+    ///
+    /// There are no jump instructions yet, so control flow is a straight line
+    /// and a single pass over the code visits every instruction.
+    /// Returns the maximum depth the stack can reach.
+    pub fn verify(&self) -> Result<usize, VerifyError> {
+        use VerifyError::*;
+
+        if self.lines.len() != self.len() {
+            return Err(LineTableMismatch {
+                code: self.len(),
+                lines: self.lines.len(),
+            });
+        }
+
+        let mut depth: usize = 0;
+        let mut max_depth: usize = 0;
+        let mut offset = 0;
+
+        while offset < self.len() {
+            let byte = self.read(offset);
+            let opcode = Opcode::decode(byte).ok_or(InvalidOpcode { offset, byte })?;
+            if offset + opcode.len() > self.len() {
+                return Err(TruncatedInstruction { offset, opcode });
+            }
+
+            let operand = if opcode.len() > 1 {
+                self.read(offset + 1)
+            } else {
+                0
+            };
+            self.verify_operand(opcode, operand, offset)?;
+
+            let (pops, pushes) = stack_effect(opcode, operand);
+            if depth < pops {
+                return Err(StackUnderflow { offset, opcode });
+            }
+            depth = depth - pops + pushes;
+            max_depth = max_depth.max(depth);
+
+            if opcode == Opcode::Return {
+                return Ok(max_depth);
+            }
+            offset += opcode.len();
+        }
+
+        Err(MissingReturn)
+    }
+
+    fn verify_operand(&self, opcode: Opcode, index: u8, offset: usize) -> Result<(), VerifyError> {
+        use VerifyError::*;
+
+        match opcode {
+            Opcode::Constant | Opcode::DefineGlobal | Opcode::GetGlobal => {
+                let constant = self
+                    .constants()
+                    .get(index as usize)
+                    .ok_or(ConstantOutOfBounds { offset, index })?;
+
+                match (opcode, constant) {
+                    (Opcode::Constant, _) | (_, Value::Ident(_)) => Ok(()),
+                    _ => Err(InvalidConstant { offset, index }),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// the number of values an instruction pops, and the number it pushes afterwards
+fn stack_effect(opcode: Opcode, operand: u8) -> (usize, usize) {
+    use Opcode::*;
+
+    let operand = operand as usize;
+    match opcode {
+        Invalid | Return => (0, 0),
+        Constant | Nil | True | False | GetGlobal => (0, 1),
+        Negate | Not | BitNot => (1, 1),
+        Add | Subtract | Multiply | Divide | Modulo | Power | FloorDivide | Equal | Greater
+        | Lesser | NotEqual | GreaterEqual | LesserEqual | And | Or | BitAnd | BitOr | BitXor
+        | ShiftLeft | ShiftRight | GetIndex => (2, 1),
+        SetIndex => (3, 1),
+        Print | Pop | DefineGlobal => (1, 0),
+        BuildMap => (operand * 2, 1),
+        Call => (operand + 1, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use std::rc::Rc;
+
+    fn number(chunk: &mut Chunk, n: f64) {
+        let idx = chunk.add_const(Value::Number(n));
+        chunk.write_two(Opcode::Constant, idx, 1);
+    }
+
+    #[test]
+    fn compiled() {
+        let mut compiler = Compiler::new("var m = {1: 2}; print m[1] + -m[1] * 4;");
+        assert!(!compiler.compile(Chunk::new()));
+        assert_eq!(compiler.take_chunk().verify(), Ok(3));
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut chunk = Chunk::new();
+        chunk.write(200, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::InvalidOpcode {
+                offset: 0,
+                byte: 200
+            })
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(Opcode::Constant, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::TruncatedInstruction {
+                offset: 0,
+                opcode: Opcode::Constant
+            })
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let mut chunk = Chunk::new();
+        chunk.write_two(Opcode::Constant, 3, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::ConstantOutOfBounds {
+                offset: 0,
+                index: 3
+            })
+        );
+
+        let mut chunk = Chunk::new();
+        number(&mut chunk, 1f64);
+        chunk.write_two(Opcode::GetGlobal, 0, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::InvalidConstant {
+                offset: 2,
+                index: 0
+            })
+        );
+
+        let mut chunk = Chunk::new();
+        let idx = chunk.add_const(Value::Ident(Rc::new("clock".to_owned())));
+        chunk.write_two(Opcode::GetGlobal, idx, 1);
+        chunk.write_two(Opcode::Call, 0, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(chunk.verify(), Ok(1));
+    }
+
+    #[test]
+    fn underflow() {
+        let mut chunk = Chunk::new();
+        number(&mut chunk, 1f64);
+        chunk.write_opcode(Opcode::Add, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::StackUnderflow {
+                offset: 2,
+                opcode: Opcode::Add
+            })
+        );
+
+        let mut chunk = Chunk::new();
+        number(&mut chunk, 1f64);
+        chunk.write_two(Opcode::BuildMap, 1, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert!(chunk.verify().is_err());
+    }
+
+    #[test]
+    fn missing_return() {
+        let mut chunk = Chunk::new();
+        number(&mut chunk, 1f64);
+        assert_eq!(chunk.verify(), Err(VerifyError::MissingReturn));
+
+        let mut chunk = Chunk::new();
+        chunk.write(1, 1);
+        chunk.lines.pop();
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::LineTableMismatch { code: 1, lines: 0 })
+        );
+    }
+}
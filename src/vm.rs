@@ -1,12 +1,103 @@
-use crate::{chunk::Chunk, opcode::Opcode, value::Value};
+use crate::{
+    chunk::Chunk,
+    compiler::Compiler,
+    opcode::Opcode,
+    prelude,
+    profile::Profile,
+    value::{Map, NativeFn, NativeFnPtr, Slot, StackSlot, Value, ValueResult},
+};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct Vm {
     chunk: Option<Chunk>,
-    pub stack: Vec<Value>,
+    pub stack: Vec<Slot>,
     pc: usize,
     globals: HashMap<Rc<String>, Value>,
+    tracing: bool,
+    trace_sink: Sink,
+    output: Sink,
+    error_output: Sink,
+    limits: Limits,
+    interrupt: Arc<AtomicBool>,
+    /// instructions executed by the outermost `interpret` call, counting
+    /// the ones natives make back into the vm
+    executed: u64,
+    call_depth: usize,
+    /// bytes of strings and maps allocated by the outermost `interpret` call
+    allocated: usize,
+    last_error: Option<RuntimeError>,
+    debug_hook: Option<Box<dyn DebugHook>>,
+    profile: Option<Profile>,
+}
+
+/// Where the vm writes a kind of output, line by line
+enum Sink {
+    Writer(Box<dyn Write>),
+    /// receives each line without its newline
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+impl Sink {
+    fn line(&mut self, text: &str) {
+        match self {
+            // a broken sink should not abort the running script, as with clox's printf
+            Sink::Writer(out) => {
+                let _ = writeln!(out, "{}", text);
+            }
+            Sink::Callback(f) => f(text),
+        }
+    }
+}
+
+/// Resource limits for running untrusted scripts. `None` means unlimited.
+/// Each limit aborts the script with its own `ErrorKind`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// instructions executed per `interpret` call, including any a native
+    /// runs by calling back into `interpret`
+    pub instructions: Option<u64>,
+    /// values on the stack at once
+    pub stack: Option<usize>,
+    /// nested calls. Lox has no functions of its own yet, so only native calls
+    /// count and a script never gets deeper than 1, unless a native calls back
+    /// into `interpret`. Until Lox has functions, this limit is effectively
+    /// inert apart from `Some(0)`, which forbids calls altogether.
+    pub call_depth: Option<usize>,
+    /// bytes allocated for strings and maps per `interpret` call, as with `instructions`.
+    /// memory is never reclaimed while a script runs, so this bounds its total allocations
+    pub heap_bytes: Option<usize>,
+}
+
+/// Stops a running vm from another thread.
+/// The vm notices the flag before its next instruction and aborts with `ErrorKind::Interrupted`.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called by the vm before every instruction while installed with `Vm::set_debug_hook`.
+/// A vm without a hook runs a dispatch loop with the call compiled out.
+pub trait DebugHook {
+    /// `offset` is the instruction about to run in `chunk`. The vm's stack
+    /// and globals are as the previous instruction left them.
+    fn before_instruction(&mut self, vm: &mut Vm, chunk: &Chunk, offset: usize) -> DebugAction;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugAction {
+    Continue,
+    /// ends the script before the instruction, like a `Return`, without an error
+    Stop,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,60 +107,415 @@ pub enum InterpretResult {
     RuntimeError,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// an error in the script itself, such as a type error
+    Script,
+    InstructionLimit,
+    StackOverflow,
+    CallDepth,
+    HeapLimit,
+    Interrupted,
+}
+
+/// An error raised while executing bytecode.
+/// Native functions return this to abort the running script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self::with_kind(ErrorKind::Script, message)
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: impl Into<String>) -> Self {
+        RuntimeError {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
 impl Vm {
+    /// Creates a vm with the built-in prelude (clock, sqrt, len, ...) installed
     pub fn new() -> Self {
+        let mut vm = Self::without_prelude();
+        prelude::install(&mut vm);
+        vm
+    }
+
+    /// Creates a vm with no globals defined
+    pub fn without_prelude() -> Self {
         let chunk = None;
         let pc = 0;
         let stack = Vec::new();
         let globals = HashMap::new();
-        Vm { chunk, pc, stack, globals }
-    }
-
-    fn current_chunk(&mut self) -> &mut Chunk {
-        self.chunk.as_mut().expect("Chunk is not initialized.")
+        Vm {
+            chunk,
+            pc,
+            stack,
+            globals,
+            tracing: false,
+            trace_sink: Sink::Writer(Box::new(io::stderr())),
+            output: Sink::Writer(Box::new(io::stdout())),
+            error_output: Sink::Writer(Box::new(io::stderr())),
+            limits: Limits::default(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            executed: 0,
+            call_depth: 0,
+            allocated: 0,
+            last_error: None,
+            debug_hook: None,
+            profile: None,
+        }
     }
 
     pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
         self.chunk = Some(chunk);
         self.pc = 0;
-        self.run()
+        // a native calling back into the vm runs on the budget of the script that called it
+        if self.call_depth == 0 {
+            self.executed = 0;
+            self.allocated = 0;
+        }
+        self.last_error = None;
+
+        match self.run() {
+            Ok(()) => InterpretResult::Ok,
+            Err(error) => {
+                self.report(&error);
+                self.last_error = Some(error);
+                InterpretResult::RuntimeError
+            }
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Returns a handle that can interrupt this vm from another thread.
+    /// An interrupt stays pending until the vm sees it, even if it is idle.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupt.clone())
+    }
+
+    /// The error the last `interpret` call failed with
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.last_error.as_ref()
+    }
+
+    /// Registers a host function as a global, callable from scripts
+    /// with exactly `arity` arguments.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFnPtr) {
+        let name = Rc::new(name.to_owned());
+        let native = NativeFn {
+            name: name.clone(),
+            arity,
+            function,
+        };
+        self.globals.insert(name, Value::Native(Rc::new(native)));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<&Value> {
+        let name = name.to_owned();
+        self.globals.get(&name)
+    }
+
+    /// Turns execution tracing on or off. While tracing, the stack and the
+    /// instruction about to run are written to the trace sink before every instruction.
+    /// Can be toggled from a native function in the middle of a script.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracing
+    }
+
+    /// Replaces the sink trace output is written to, which is stderr by default
+    pub fn set_trace_sink(&mut self, sink: impl Write + 'static) {
+        self.trace_sink = Sink::Writer(Box::new(sink));
+    }
+
+    /// Replaces the sink `print` writes to, which is stdout by default
+    pub fn set_output(&mut self, sink: impl Write + 'static) {
+        self.output = Sink::Writer(Box::new(sink));
+    }
+
+    /// Passes every printed line to `f`, without its newline
+    pub fn set_output_fn(&mut self, f: impl FnMut(&str) + 'static) {
+        self.output = Sink::Callback(Box::new(f));
+    }
+
+    /// Replaces the sink runtime errors are reported to, which is stderr by default
+    pub fn set_error_output(&mut self, sink: impl Write + 'static) {
+        self.error_output = Sink::Writer(Box::new(sink));
+    }
+
+    /// Passes every runtime error report to `f`.
+    /// A report is the error message and the line it occurred on, separated by a newline.
+    pub fn set_error_fn(&mut self, f: impl FnMut(&str) + 'static) {
+        self.error_output = Sink::Callback(Box::new(f));
+    }
+
+    pub fn set_debug_hook(&mut self, hook: impl DebugHook + 'static) {
+        self.debug_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_debug_hook(&mut self) {
+        self.debug_hook = None;
+    }
+
+    /// Turns profiling on or off. While profiling, every instruction executed is
+    /// counted, across `interpret` calls until the profile is taken.
+    /// Turned on while a script runs, it takes effect from the next `interpret` call.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        match profiling {
+            true => {
+                self.profile.get_or_insert_with(Profile::default);
+            }
+            false => self.profile = None,
+        }
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Takes the counts gathered so far, leaving a fresh profile if profiling
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let profile = self.profile.take();
+        if profile.is_some() {
+            self.profile = Some(Profile::default());
+        }
+        profile
     }
 
-    fn run(&mut self) -> InterpretResult {
+    /// Takes back the chunk given to the last `interpret` call
+    pub fn take_chunk(&mut self) -> Option<Chunk> {
+        self.chunk.take()
+    }
+
+    /// The globals defined so far, in no particular order
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Evaluates an expression against the current globals, as a debugger does
+    /// while the vm is paused, and returns the value as `print` shows it.
+    /// Runs on a separate stack, so it is safe to call from a `DebugHook`.
+    pub fn evaluate(&mut self, expression: &str) -> Result<String, String> {
+        let source = format!("print {};", expression);
+        let mut compiler = Compiler::new(&source);
+        compiler.set_print_errors(false);
+        if compiler.compile(Chunk::new()) {
+            return Err(compiler.errors()[0].to_string());
+        }
+        let chunk = compiler.take_chunk();
+
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let mut scratch = Vm::without_prelude();
+        scratch.globals = std::mem::take(&mut self.globals);
+        scratch.limits = self.limits;
+        let out = printed.clone();
+        scratch.set_output_fn(move |line| out.borrow_mut().push(line.to_owned()));
+        // the error is returned instead
+        scratch.set_error_fn(|_| ());
+
+        scratch.interpret(chunk);
+        // side effects on maps and new variables stay, as in the script
+        self.globals = std::mem::take(&mut scratch.globals);
+
+        match scratch.last_error {
+            Some(error) => Err(error.message),
+            None => Ok(printed.borrow().join("\n")),
+        }
+    }
+
+    fn trace(&mut self, chunk: &Chunk, offset: usize) {
+        let mut line = String::from("          ");
+        for slot in &self.stack {
+            line.push_str(&format!("[ {} ]", slot.to_value()));
+        }
+
+        self.trace_sink.line(&line);
+        self.trace_sink.line(&chunk.decode(offset).to_string());
+    }
+
+    fn report(&mut self, error: &RuntimeError) {
+        let line = match self.chunk {
+            Some(ref chunk) if self.pc > 0 => chunk.lines[self.pc - 1],
+            _ => 0,
+        };
+        self.error_output
+            .line(&format!("{}\n[line {}] in script", error, line));
+        self.stack.clear();
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        // the chunk is moved out while executing, so that the dispatch loop can hold
+        // on to its code without going through `self.chunk` for every byte
+        let chunk = self.chunk.take().expect("Chunk is not initialized.");
+        let mut pc = self.pc;
+
+        // hooks and profiling get their own copy of the dispatch loop
+        let result = if self.debug_hook.is_some() || self.profile.is_some() {
+            self.execute::<true>(&chunk, &mut pc)
+        } else {
+            self.execute::<false>(&chunk, &mut pc)
+        };
+
+        self.pc = pc;
+        self.chunk = Some(chunk);
+        result
+    }
+
+    // maps hash by identity, so the interior mutability of map keys is harmless
+    #[allow(clippy::mutable_key_type)]
+    fn execute<const INSTRUMENTED: bool>(&mut self, chunk: &Chunk, pc: &mut usize) -> Result<(), RuntimeError> {
+        let code = chunk.code();
+
+        macro_rules! read_byte {
+            () => {{
+                let Some(&byte) = code.get(*pc) else {
+                    return Err(RuntimeError::new("Unexpected end of code."));
+                };
+                *pc += 1;
+                byte
+            }};
+        }
+
+        macro_rules! read_constant {
+            () => {{
+                let index = read_byte!();
+                let Some(value) = chunk.constants().get(index as usize) else {
+                    return Err(RuntimeError::new(format!("No constant at index {}.", index)));
+                };
+                value
+            }};
+        }
+
+        let max_instructions = self.limits.instructions.unwrap_or(u64::MAX);
+        let max_stack = self.limits.stack.unwrap_or(usize::MAX);
+
         loop {
-            let opcode: Opcode = self.read().into();
+            // every instruction pushes at most one value, so an overflow
+            // is caught before the instruction that follows it
+            self.executed += 1;
+            if self.executed > max_instructions {
+                return Err(RuntimeError::with_kind(
+                    ErrorKind::InstructionLimit,
+                    format!("Instruction limit of {} exceeded.", max_instructions),
+                ));
+            }
+            if self.stack.len() > max_stack {
+                return Err(RuntimeError::with_kind(ErrorKind::StackOverflow, "Stack overflow."));
+            }
+            if self.interrupt.load(Ordering::Relaxed) {
+                self.interrupt.store(false, Ordering::Relaxed);
+                return Err(RuntimeError::with_kind(ErrorKind::Interrupted, "Interrupted."));
+            }
+
+            // fetched first, so that the hooks below only see offsets inside the code
+            let offset = *pc;
+            let byte = read_byte!();
+
+            if INSTRUMENTED {
+                if let Some(ref mut profile) = self.profile {
+                    profile.record(chunk, offset);
+                }
+                if let Some(mut hook) = self.debug_hook.take() {
+                    let action = hook.before_instruction(self, chunk, offset);
+                    self.debug_hook.get_or_insert(hook);
+                    if action == DebugAction::Stop {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.tracing {
+                self.trace(chunk, offset);
+            }
+
+            let opcode = Opcode::decode(byte).unwrap_or(Opcode::Invalid);
             match opcode {
-                Opcode::Invalid => panic!("Invalid instruction."),
+                Opcode::Invalid => {
+                    return Err(RuntimeError::new(format!("Invalid opcode {}.", byte)));
+                }
                 Opcode::Return => {
-                    return InterpretResult::Ok;
+                    return Ok(());
                 }
                 Opcode::Constant => {
-                    let val = self.read_constant().clone();
+                    let val = read_constant!().clone();
                     self.push(val);
                 }
                 Opcode::Negate => {
-                    let popped = self.pop();
-                    self.push(-popped);
+                    let popped = self.pop()?;
+                    self.push((-popped)?);
                 }
                 Opcode::Add => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a + b);
+                    self.binary_op(|a, b| a + b, |a, b| a + b)?;
                 }
                 Opcode::Subtract => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a - b);
+                    self.binary_op(|a, b| a - b, |a, b| a - b)?;
                 }
                 Opcode::Multiply => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a * b);
+                    self.binary_op(|a, b| a * b, |a, b| a * b)?;
                 }
                 Opcode::Divide => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a / b);
+                    self.binary_op(|a, b| a / b, |a, b| a / b)?;
+                }
+                Opcode::Modulo => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a % b)?);
+                }
+                Opcode::Power => {
+                    let (a, b) = self.pop_two()?;
+                    self.push(a.pow(b)?);
+                }
+                Opcode::FloorDivide => {
+                    let (a, b) = self.pop_two()?;
+                    self.push(a.floor_div(b)?);
+                }
+                Opcode::BitAnd => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a & b)?);
+                }
+                Opcode::BitOr => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a | b)?);
+                }
+                Opcode::BitXor => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a ^ b)?);
+                }
+                Opcode::BitNot => {
+                    let popped = self.pop()?;
+                    self.push(popped.bit_not()?);
+                }
+                Opcode::ShiftLeft => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a << b)?);
+                }
+                Opcode::ShiftRight => {
+                    let (a, b) = self.pop_two()?;
+                    self.push((a >> b)?);
                 }
                 Opcode::Nil => {
                     self.push(Value::Nil);
@@ -81,39 +527,77 @@ impl Vm {
                     self.push(Value::Bool(false));
                 }
                 Opcode::Not => {
-                    let b = self.pop();
+                    let b = self.pop()?;
                     self.push(Value::Bool(!b.truthy()));
                 }
                 Opcode::Equal => {
-                    let (a, b) = self.pop_two();
+                    let (a, b) = self.pop_two()?;
                     self.push(Value::Bool(a == b));
                 }
                 Opcode::Greater => {
                     use std::cmp::Ordering;
 
-                    let (a, b) = self.pop_two();
+                    let (a, b) = self.pop_two()?;
                     match a.partial_cmp(&b) {
                         Some(Ordering::Less) | Some(Ordering::Equal) => {
                             self.push(Value::Bool(false))
                         }
                         Some(Ordering::Greater) => self.push(Value::Bool(true)),
-                        None => panic!("Invalid comparison: {} > {}", a, b),
+                        None => {
+                            return Err(RuntimeError::new(format!(
+                                "Invalid comparison: {} > {}",
+                                a, b
+                            )))
+                        }
                     };
                 }
                 Opcode::Lesser => {
                     use std::cmp::Ordering;
 
-                    let (a, b) = self.pop_two();
+                    let (a, b) = self.pop_two()?;
                     match a.partial_cmp(&b) {
                         Some(Ordering::Less) => self.push(Value::Bool(true)),
                         Some(Ordering::Equal) | Some(Ordering::Greater) => {
                             self.push(Value::Bool(false))
                         }
-                        None => panic!("Invalid comparison: {} > {}", a, b),
+                        None => {
+                            return Err(RuntimeError::new(format!(
+                                "Invalid comparison: {} < {}",
+                                a, b
+                            )))
+                        }
+                    };
+                }
+                Opcode::NotEqual => {
+                    let (a, b) = self.pop_two()?;
+                    self.push(Value::Bool(a != b));
+                }
+                Opcode::GreaterEqual => {
+                    let (a, b) = self.pop_two()?;
+                    match a.partial_cmp(&b) {
+                        Some(ordering) => self.push(Value::Bool(ordering.is_ge())),
+                        None => {
+                            return Err(RuntimeError::new(format!(
+                                "Invalid comparison: {} >= {}",
+                                a, b
+                            )))
+                        }
+                    };
+                }
+                Opcode::LesserEqual => {
+                    let (a, b) = self.pop_two()?;
+                    match a.partial_cmp(&b) {
+                        Some(ordering) => self.push(Value::Bool(ordering.is_le())),
+                        None => {
+                            return Err(RuntimeError::new(format!(
+                                "Invalid comparison: {} <= {}",
+                                a, b
+                            )))
+                        }
                     };
                 }
                 Opcode::And => {
-                    let (a, b) = self.pop_two();
+                    let (a, b) = self.pop_two()?;
 
                     // short circuiting
                     if a.truthy() {
@@ -123,7 +607,7 @@ impl Vm {
                     }
                 },
                 Opcode::Or => {
-                    let (a, b) = self.pop_two();
+                    let (a, b) = self.pop_two()?;
 
                     // short circuiting
                     if a.truthy() {
@@ -133,72 +617,243 @@ impl Vm {
                     }
                 },
                 Opcode::Print => {
-                    let v = self.pop();
-                    println!("{}", v);
+                    let v = self.pop()?;
+                    self.output.line(&v.to_string());
                 },
                 Opcode::Pop => {
-                    self.pop();
+                    self.pop()?;
                 },
                 Opcode::DefineGlobal => {
-                    let value = self.peek(0).clone();
-                    let ident = self.read_constant().clone();
+                    let value = self.pop()?;
 
-                    match ident {
-                        Value::Ident(ident) => self.globals.insert(ident, value),
-                        _ => panic!("The left hand side of a global assignment should be a variable."),
+                    match read_constant!() {
+                        Value::Ident(ident) => match self.globals.get_mut(ident) {
+                            Some(slot) => *slot = value,
+                            None => {
+                                self.globals.insert(ident.clone(), value);
+                            }
+                        },
+                        v => return Err(expected_variable(v)),
                     };
                 },
                 Opcode::GetGlobal => {
-                    let ident = self.read_constant().clone();
-
-                    match ident {
-                        Value::Ident(ref ident) => {
+                    match read_constant!() {
+                        Value::Ident(ident) => {
                             let val = match self.globals.get(ident) {
                                 Some(v) => v.clone(),
-                                None => panic!("Variable {} empty in globals table.", ident),
+                                None => {
+                                    return Err(RuntimeError::new(format!(
+                                        "Undefined variable '{}'.",
+                                        ident
+                                    )))
+                                }
                             };
                             self.push(val);
                         },
-                        _ => panic!("Accessing a global variable is only possible with a variable."),
+                        v => return Err(expected_variable(v)),
+                    }
+                },
+                Opcode::BuildMap => {
+                    let count = read_byte!() as usize;
+                    self.ensure_stack(count * 2)?;
+                    let entries = self.stack.split_off(self.stack.len() - count * 2);
+
+                    let mut map = Map::with_capacity(count);
+                    let mut entries = entries.into_iter().map(Slot::into_value);
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        if !key.hashable() {
+                            return Err(RuntimeError::new(format!("Invalid map key {}.", key)));
+                        }
+                        map.insert(key, value);
+                    }
+                    self.charge(count * MAP_ENTRY_BYTES)?;
+                    self.push(Value::Map(Rc::new(RefCell::new(map))));
+                },
+                Opcode::GetIndex => {
+                    let (map, key) = self.pop_two()?;
+
+                    match map {
+                        Value::Map(ref map) => {
+                            let val = map.borrow().get(&key).cloned().unwrap_or(Value::Nil);
+                            self.push(val);
+                        },
+                        _ => {
+                            return Err(RuntimeError::new(format!(
+                                "Only maps can be indexed. Encountered {}",
+                                map
+                            )))
+                        }
+                    }
+                },
+                Opcode::SetIndex => {
+                    let value = self.pop()?;
+                    let (map, key) = self.pop_two()?;
+
+                    match map {
+                        Value::Map(ref map) => {
+                            if !key.hashable() {
+                                return Err(RuntimeError::new(format!("Invalid map key {}.", key)));
+                            }
+                            self.charge(MAP_ENTRY_BYTES)?;
+                            map.borrow_mut().insert(key, value.clone());
+                            self.push(value);
+                        },
+                        _ => {
+                            return Err(RuntimeError::new(format!(
+                                "Only maps can be indexed. Encountered {}",
+                                map
+                            )))
+                        }
                     }
+                },
+                Opcode::Call => {
+                    let argc = read_byte!() as usize;
+                    self.call_value(argc)?;
                 }
             }
         }
     }
 
-    fn read(&mut self) -> u8 {
-        let pc = self.pc;
-        let chunk = self.current_chunk();
-        let inst = chunk.read(pc);
-        self.pc += 1;
-        inst
+    fn call_value(&mut self, argc: usize) -> Result<(), RuntimeError> {
+        let callee = self.peek(argc)?;
+
+        match callee {
+            Value::Native(native) => {
+                if argc != native.arity {
+                    return Err(RuntimeError::new(format!(
+                        "Expected {} arguments but got {}.",
+                        native.arity, argc
+                    )));
+                }
+
+                let args: Vec<Value> = self
+                    .stack
+                    .split_off(self.stack.len() - argc)
+                    .into_iter()
+                    .map(Slot::into_value)
+                    .collect();
+                self.pop()?;
+
+                if self.call_depth >= self.limits.call_depth.unwrap_or(usize::MAX) {
+                    return Err(RuntimeError::with_kind(
+                        ErrorKind::CallDepth,
+                        "Call depth limit exceeded.",
+                    ));
+                }
+                self.call_depth += 1;
+                let result = (native.function)(self, &args);
+                self.call_depth -= 1;
+
+                let result = result?;
+                self.charge(heap_size(&result))?;
+                self.push(result);
+                Ok(())
+            }
+            _ => Err(RuntimeError::new("Can only call functions.")),
+        }
     }
 
-    fn read_value(&mut self, addr: u8) -> &Value {
-        self.current_chunk().read_value(addr)
+    /// numbers are combined in place on the stack without unpacking them into
+    /// a `Value`. other operands go through the generic operation.
+    #[inline]
+    fn binary_op(
+        &mut self,
+        number_op: fn(f64, f64) -> f64,
+        value_op: fn(Value, Value) -> ValueResult,
+    ) -> Result<(), RuntimeError> {
+        self.ensure_stack(2)?;
+        let len = self.stack.len();
+        if let (Some(a), Some(b)) = (self.stack[len - 2].as_number(), self.stack[len - 1].as_number()) {
+            self.stack.pop();
+            self.stack[len - 2] = Slot::from_number(number_op(a, b));
+            return Ok(());
+        }
+
+        let (a, b) = self.pop_two()?;
+        let result = value_op(a, b)?;
+        self.charge(heap_size(&result))?;
+        self.push(result);
+        Ok(())
     }
 
-    fn read_constant(&mut self) -> &Value {
-        let addr = self.read();
-        self.read_value(addr)
+    /// accounts for `bytes` newly allocated by the script
+    fn charge(&mut self, bytes: usize) -> Result<(), RuntimeError> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.limits.heap_bytes {
+            Some(max) if self.allocated > max => Err(RuntimeError::with_kind(
+                ErrorKind::HeapLimit,
+                format!("Heap limit of {} bytes exceeded.", max),
+            )),
+            _ => Ok(()),
+        }
     }
 
+    #[inline]
     fn push(&mut self, value: Value) {
-        self.stack.push(value);
+        self.stack.push(Slot::from_value(value));
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("Stack is empty.")
+    #[inline]
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        match self.stack.pop() {
+            Some(slot) => Ok(slot.into_value()),
+            None => Err(stack_underflow()),
+        }
     }
 
-    fn pop_two(&mut self) -> (Value, Value) {
-        let b = self.pop();
-        let a = self.pop();
-        (a, b)
+    #[inline]
+    fn pop_two(&mut self) -> Result<(Value, Value), RuntimeError> {
+        self.ensure_stack(2)?;
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))
     }
 
-    fn peek(&self, distance: usize) -> &Value {
-        &self.stack[self.stack.len() - 1 - distance]
+    fn peek(&self, distance: usize) -> Result<Value, RuntimeError> {
+        self.ensure_stack(distance + 1)?;
+        Ok(self.stack[self.stack.len() - 1 - distance].to_value())
+    }
+
+    /// fails unless the stack holds at least `count` values
+    #[inline]
+    fn ensure_stack(&self, count: usize) -> Result<(), RuntimeError> {
+        match self.stack.len() < count {
+            true => Err(stack_underflow()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// an estimate of the memory taken by one map entry
+const MAP_ENTRY_BYTES: usize = 2 * std::mem::size_of::<Value>();
+
+/// the bytes allocated for a freshly created value.
+/// maps are charged per entry as they are built, so they count as empty here
+fn heap_size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => std::mem::size_of::<String>() + s.len(),
+        Value::Map(_) => std::mem::size_of::<RefCell<Map>>(),
+        _ => 0,
+    }
+}
+
+/// the error for a global instruction whose operand is not an identifier,
+/// which only a hand-made chunk can contain
+fn expected_variable(value: &Value) -> RuntimeError {
+    RuntimeError::new(format!("Expected a variable name. Encountered {}.", value))
+}
+
+/// the error for an instruction that pops more values than the stack holds,
+/// which only a hand-made chunk can contain
+#[cold]
+#[inline(never)]
+fn stack_underflow() -> RuntimeError {
+    RuntimeError::new("Stack underflow.")
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -234,4 +889,458 @@ mod tests {
         chunk.write_opcode(Opcode::Return, 1);
         assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
     }
+
+    fn binary_op(opcode: Opcode, a: Value, b: Value) -> (Vm, InterpretResult) {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        make_const(&mut chunk, a);
+        make_const(&mut chunk, b);
+        chunk.write_opcode(opcode, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        let result = vm.interpret(chunk);
+        (vm, result)
+    }
+
+    #[test]
+    fn modulo_and_floor_divide() {
+        let (vm, _) = binary_op(Opcode::Modulo, Value::Number(-7f64), Value::Number(3f64));
+        assert_eq!(vm.stack, vec![Value::Number(2f64)]);
+        let (vm, _) = binary_op(Opcode::FloorDivide, Value::Number(-7f64), Value::Number(2f64));
+        assert_eq!(vm.stack, vec![Value::Number(-4f64)]);
+        let (vm, _) = binary_op(Opcode::Power, Value::Number(2f64), Value::Number(10f64));
+        assert_eq!(vm.stack, vec![Value::Number(1024f64)]);
+    }
+
+    #[test]
+    fn arithmetic_on_non_numbers() {
+        let (_, result) = binary_op(Opcode::Modulo, Value::Nil, Value::Number(3f64));
+        assert_eq!(result, InterpretResult::RuntimeError);
+        let (_, result) = binary_op(Opcode::Subtract, Value::Bool(true), Value::Number(3f64));
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn bitwise() {
+        let (vm, _) = binary_op(Opcode::BitXor, Value::Number(12f64), Value::Number(10f64));
+        assert_eq!(vm.stack, vec![Value::Number(6f64)]);
+        let (vm, _) = binary_op(Opcode::ShiftRight, Value::Number(-8f64), Value::Number(1f64));
+        assert_eq!(vm.stack, vec![Value::Number(-4f64)]);
+        let (_, result) = binary_op(Opcode::BitAnd, Value::Number(1.5), Value::Number(1f64));
+        assert_eq!(result, InterpretResult::RuntimeError);
+        let (_, result) = binary_op(Opcode::ShiftLeft, Value::Number(1f64), Value::Number(64f64));
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    fn make_str(chunk: &mut Chunk, s: &str) {
+        make_const(chunk, Value::String(Rc::new(s.to_owned())));
+    }
+
+    #[test]
+    fn map_index() {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        make_str(&mut chunk, "a");
+        make_const(&mut chunk, Value::Number(1f64));
+        make_str(&mut chunk, "b");
+        make_const(&mut chunk, Value::Number(2f64));
+        chunk.write_two(Opcode::BuildMap, 2, 1);
+        make_str(&mut chunk, "b");
+        chunk.write_opcode(Opcode::GetIndex, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        assert_eq!(vm.stack, vec![Value::Number(2f64)]);
+    }
+
+    #[test]
+    fn map_set_index() {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        let ident = chunk.add_const(Value::Ident(Rc::new("m".to_owned())));
+        chunk.write_two(Opcode::BuildMap, 0, 1);
+        chunk.write_two(Opcode::DefineGlobal, ident, 1);
+        chunk.write_two(Opcode::GetGlobal, ident, 1);
+        make_const(&mut chunk, Value::Number(-0f64));
+        make_const(&mut chunk, Value::Bool(true));
+        chunk.write_opcode(Opcode::SetIndex, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+
+        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+        let Some(Value::Map(map)) = vm.get_global("m") else {
+            panic!("Expected a map in 'm'.");
+        };
+        assert_eq!(map.borrow().get(&Value::Number(0f64)), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn string_equality() {
+        // strings compare by content, not by identity
+        let hello = || Value::String(Rc::new("hello".to_owned()));
+        let (vm, _) = binary_op(Opcode::Equal, hello(), hello());
+        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+        let (vm, _) = binary_op(Opcode::Equal, hello(), Value::String(Rc::new("world".to_owned())));
+        assert_eq!(vm.stack, vec![Value::Bool(false)]);
+    }
+
+    #[test]
+    fn global_with_non_ident_operand() {
+        for opcode in [Opcode::DefineGlobal, Opcode::GetGlobal] {
+            let mut vm = Vm::new();
+            vm.set_error_fn(|_| ());
+            let mut chunk = Chunk::new();
+            make_const(&mut chunk, Value::Nil);
+            let index = chunk.add_const(Value::Number(1f64));
+            chunk.write_two(opcode, index, 1);
+            chunk.write_opcode(Opcode::Return, 1);
+            assert_eq!(vm.interpret(chunk), InterpretResult::RuntimeError);
+            assert_eq!(
+                vm.last_error().unwrap().message,
+                "Expected a variable name. Encountered 1."
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_code() {
+        let run = |code: Vec<u8>| {
+            let lines = vec![1; code.len()];
+            let mut vm = Vm::new();
+            vm.set_error_fn(|_| ());
+            assert_eq!(
+                vm.interpret(Chunk::from_parts(code, lines, Vec::new())),
+                InterpretResult::RuntimeError
+            );
+            vm.last_error().unwrap().message.clone()
+        };
+
+        assert_eq!(run(vec![200, Opcode::Return.into()]), "Invalid opcode 200.");
+        assert_eq!(run(vec![Opcode::Pop.into(), Opcode::Return.into()]), "Stack underflow.");
+        assert_eq!(
+            run(vec![Opcode::Nil.into(), Opcode::Add.into(), Opcode::Return.into()]),
+            "Stack underflow."
+        );
+        assert_eq!(
+            run(vec![Opcode::BuildMap.into(), 1, Opcode::Return.into()]),
+            "Stack underflow."
+        );
+        assert_eq!(
+            run(vec![Opcode::Nil.into(), Opcode::Call.into(), 1, Opcode::Return.into()]),
+            "Stack underflow."
+        );
+        assert_eq!(run(Vec::new()), "Unexpected end of code.");
+        assert_eq!(run(vec![Opcode::Constant.into()]), "Unexpected end of code.");
+        assert_eq!(
+            run(vec![Opcode::GetGlobal.into(), 3, Opcode::Return.into()]),
+            "No constant at index 3."
+        );
+    }
+
+    fn native_add(_vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+        match (&args[0], &args[1]) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
+        }
+    }
+
+    fn call_add(args: &[Value]) -> (Vm, InterpretResult) {
+        let mut vm = Vm::new();
+        vm.define_native("add", 2, native_add);
+
+        let mut chunk = Chunk::new();
+        let ident = chunk.add_const(Value::Ident(Rc::new("add".to_owned())));
+        chunk.write_two(Opcode::GetGlobal, ident, 1);
+        for arg in args {
+            make_const(&mut chunk, arg.clone());
+        }
+        chunk.write_two(Opcode::Call, args.len() as u8, 1);
+        chunk.write_opcode(Opcode::Return, 1);
+
+        let result = vm.interpret(chunk);
+        (vm, result)
+    }
+
+    #[test]
+    fn native_call() {
+        let (vm, result) = call_add(&[Value::Number(1f64), Value::Number(2f64)]);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack, vec![Value::Number(3f64)]);
+    }
+
+    #[test]
+    fn native_arity() {
+        let (_, result) = call_add(&[Value::Number(1f64)]);
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn native_error() {
+        let (_, result) = call_add(&[Value::Number(1f64), Value::Nil]);
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    fn run_asm(source: &str) -> (Vm, InterpretResult) {
+        let chunk = Chunk::assemble(source).expect("Invalid assembly.");
+        let mut vm = Vm::new();
+        let result = vm.interpret(chunk);
+        (vm, result)
+    }
+
+    #[test]
+    fn undefined_global() {
+        let (_, result) = run_asm(
+            ".constants
+                @nowhere
+            .code
+                GetGlobal 0
+                Return",
+        );
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn index_non_map() {
+        let (_, result) = run_asm(
+            ".constants
+                \"str\"
+                1
+            .code
+                Constant 0
+                Constant 1
+                GetIndex
+                Return",
+        );
+        assert_eq!(result, InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn nan_map_key() {
+        let (vm, result) = run_asm(
+            ".constants
+                0
+            .code
+                Constant 0
+                Constant 0
+                Divide
+                True
+                BuildMap 1
+                Return",
+        );
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert!(vm.stack.is_empty());
+    }
+
+    /// a trace sink the test can still read after handing it to the vm
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stop_tracing(vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+        vm.set_tracing(false);
+        Ok(Value::Nil)
+    }
+
+    #[test]
+    fn tracing() {
+        let buf = SharedBuf::default();
+        let mut vm = Vm::new();
+        vm.define_native("stop", 0, stop_tracing);
+        vm.set_trace_sink(buf.clone());
+        vm.set_tracing(true);
+
+        let chunk = Chunk::assemble(
+            ".constants
+                1
+                @stop
+            .code
+                .line 3
+                Constant 0
+                Negate
+                GetGlobal 1
+                Call 0
+                Pop
+                Return",
+        )
+        .unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        assert!(!vm.is_tracing());
+
+        let trace = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "          \n\
+             0000    3 Constant         0    '1'\n\
+             \x20         [ 1 ]\n\
+             0002    | Negate\n\
+             \x20         [ -1 ]\n\
+             0003    | GetGlobal        1    '@stop'\n\
+             \x20         [ -1 ][ <native fn stop> ]\n\
+             0005    | Call             0\n"
+        );
+    }
+
+    #[test]
+    fn statements_leave_nothing_on_the_stack() {
+        let mut compiler = crate::compiler::Compiler::new("var a = 1; var m = {}; m[a] = a; a; clock();");
+        assert!(!compiler.compile(Chunk::new()));
+
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(compiler.take_chunk()), InterpretResult::Ok);
+        assert!(vm.stack.is_empty());
+    }
+
+    fn run_limited(source: &str, limits: Limits) -> Option<ErrorKind> {
+        let mut compiler = crate::compiler::Compiler::new(source);
+        assert!(!compiler.compile(Chunk::new()));
+
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        vm.interpret(compiler.take_chunk());
+        vm.last_error().map(|e| e.kind)
+    }
+
+    #[test]
+    fn limits() {
+        let source = "var a = 1 + 2 + 3; a; a; a;";
+        assert_eq!(run_limited(source, Limits::default()), None);
+
+        let instructions = Some(5);
+        assert_eq!(
+            run_limited(source, Limits { instructions, ..Limits::default() }),
+            Some(ErrorKind::InstructionLimit)
+        );
+
+        // statements leave nothing behind on the stack
+        let stack = Some(3);
+        assert_eq!(run_limited(source, Limits { stack, ..Limits::default() }), None);
+        assert_eq!(
+            run_limited("print {1: {2: {3: 4}}};", Limits { stack, ..Limits::default() }),
+            Some(ErrorKind::StackOverflow)
+        );
+
+        let call_depth = Some(0);
+        assert_eq!(
+            run_limited("clock();", Limits { call_depth, ..Limits::default() }),
+            Some(ErrorKind::CallDepth)
+        );
+
+        let heap_bytes = Some(64);
+        let concat = "var s = \"0123456789\"; print s + s + s + s;";
+        assert_eq!(
+            run_limited(concat, Limits { heap_bytes, ..Limits::default() }),
+            Some(ErrorKind::HeapLimit)
+        );
+        assert_eq!(
+            run_limited("print {1: 2, 3: 4, 5: 6};", Limits { heap_bytes, ..Limits::default() }),
+            Some(ErrorKind::HeapLimit)
+        );
+    }
+
+    fn run_nested(vm: &mut Vm, _args: &[Value]) -> Result<Value, RuntimeError> {
+        let chunk = Chunk::assemble(
+            ".constants\n@run_nested\n.code\nGetGlobal 0\nCall 0\nPop\nReturn",
+        )
+        .unwrap();
+        match vm.interpret(chunk) {
+            InterpretResult::Ok => Ok(Value::Nil),
+            _ => Err(vm.last_error().unwrap().clone()),
+        }
+    }
+
+    #[test]
+    fn limits_span_nested_interpret_calls() {
+        for (limits, kind) in [
+            (Limits { instructions: Some(100), ..Limits::default() }, ErrorKind::InstructionLimit),
+            (Limits { call_depth: Some(10), ..Limits::default() }, ErrorKind::CallDepth),
+        ] {
+            let mut vm = Vm::new();
+            vm.set_limits(limits);
+            vm.set_error_fn(|_| ());
+            vm.define_native("run_nested", 0, run_nested);
+            assert_eq!(run_nested(&mut vm, &[]).map_err(|e| e.kind), Err(kind));
+        }
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut vm = Vm::new();
+        let handle = vm.interrupt_handle();
+        std::thread::spawn(move || handle.interrupt()).join().unwrap();
+
+        let chunk = Chunk::assemble(".code\nNil\nPop\nReturn").unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::RuntimeError);
+        assert_eq!(vm.last_error().unwrap().kind, ErrorKind::Interrupted);
+
+        // the interrupt is consumed
+        let chunk = Chunk::assemble(".code\nNil\nPop\nReturn").unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn output_sinks() {
+        let out = SharedBuf::default();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+
+        let mut vm = Vm::new();
+        vm.set_output(out.clone());
+        let sink = errors.clone();
+        vm.set_error_fn(move |report| sink.borrow_mut().push(report.to_owned()));
+
+        let chunk = Chunk::assemble(
+            ".constants
+                \"hi\"
+                @nowhere
+            .code
+                Constant 0
+                Print
+                True
+                Print
+                .line 2
+                GetGlobal 1
+                Return",
+        )
+        .unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::RuntimeError);
+        assert_eq!(String::from_utf8(out.0.borrow().clone()).unwrap(), "\"hi\"\nTrue\n");
+        assert_eq!(
+            *errors.borrow(),
+            vec!["Undefined variable 'nowhere'.\n[line 2] in script".to_owned()]
+        );
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        vm.set_output_fn(move |line| sink.borrow_mut().push(line.to_owned()));
+        let chunk = Chunk::assemble(".code\nNil\nPrint\nReturn").unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        assert_eq!(*lines.borrow(), vec!["Nil".to_owned()]);
+    }
+
+    #[test]
+    fn profiling() {
+        let chunk = || Chunk::assemble(".code\nNil\n.line 2\nNil\nNot\nPop\nPop\nReturn").unwrap();
+
+        let mut vm = Vm::new();
+        vm.interpret(chunk());
+        assert!(vm.profile().is_none());
+
+        vm.set_profiling(true);
+        vm.interpret(chunk());
+        vm.interpret(chunk());
+        let profile = vm.take_profile().unwrap();
+        assert_eq!(profile.total(), 12);
+        assert_eq!(profile.opcode_count(Opcode::Nil), 4);
+        assert_eq!((profile.line_count(1), profile.line_count(2)), (2, 10));
+        assert_eq!(vm.profile().map(Profile::total), Some(0));
+
+        vm.set_profiling(false);
+        assert!(vm.take_profile().is_none());
+    }
 }
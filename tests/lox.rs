@@ -0,0 +1,34 @@
+//! Runs every `.lox` script under `tests/lox` with `rustox::testing`, as
+//! `rustox test` does, and checks it against the annotations in its comments.
+//! Set `LOX_TEST_DIR` to run the scripts of another directory instead.
+
+use rustox::testing::{self, Expectations};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn lox_scripts() {
+    let dir = std::env::var_os("LOX_TEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"));
+
+    let mut paths = Vec::new();
+    testing::find_scripts(&dir, &mut paths).expect("Could not read test directory.");
+    paths.sort();
+    assert!(!paths.is_empty(), "No scripts found in {}.", dir.display());
+
+    let mut failed = 0;
+    for path in &paths {
+        let source = fs::read_to_string(path).expect("Could not read test script.");
+        let failures = Expectations::parse(&source).check(&testing::run(&source).outcome);
+        if !failures.is_empty() {
+            failed += 1;
+            eprintln!("FAIL {}", path.display());
+            for failure in failures {
+                eprintln!("     {}", failure);
+            }
+        }
+    }
+
+    assert_eq!(failed, 0, "{} of {} scripts failed.", failed, paths.len());
+}
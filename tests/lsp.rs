@@ -0,0 +1,242 @@
+//! Drives `rustox lsp` through a scripted session over its stdio,
+//! the way an editor would.
+
+use rustox::json::Json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+const URI: &str = "file:///session.lox";
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u32,
+}
+
+impl Client {
+    fn start() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rustox"))
+            .arg("lsp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to start the server.");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Client {
+            child,
+            stdin,
+            stdout,
+            next_id: 1,
+        }
+    }
+
+    fn send(&mut self, message: Json) {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Json {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            self.stdout.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        Json::parse(std::str::from_utf8(&body).unwrap()).unwrap()
+    }
+
+    fn notify(&mut self, method: &str, params: Json) {
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]));
+    }
+
+    /// sends a request and returns its response
+    fn request(&mut self, method: &str, params: Json) -> Json {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ]));
+
+        let response = self.receive();
+        assert_eq!(response.get("id"), Some(&Json::from(id)));
+        response
+    }
+
+    /// the diagnostics the server publishes after a notification about the document
+    fn diagnostics(&mut self) -> Vec<Json> {
+        let message = self.receive();
+        assert_eq!(
+            get(&message, &["method"]).as_str(),
+            Some("textDocument/publishDiagnostics")
+        );
+        assert_eq!(get(&message, &["params", "uri"]).as_str(), Some(URI));
+        get(&message, &["params", "diagnostics"])
+            .as_array()
+            .unwrap()
+            .to_vec()
+    }
+
+    fn change(&mut self, version: u32, text: &str) -> Vec<Json> {
+        self.notify(
+            "textDocument/didChange",
+            Json::object([
+                (
+                    "textDocument",
+                    Json::object([("uri", URI.into()), ("version", version.into())]),
+                ),
+                (
+                    "contentChanges",
+                    vec![Json::object([("text", text.into())])].into(),
+                ),
+            ]),
+        );
+        self.diagnostics()
+    }
+
+    fn exit(mut self) -> Option<i32> {
+        self.notify("exit", Json::Null);
+        self.child.wait().unwrap().code()
+    }
+}
+
+/// follows a path of keys, panicking if one is missing
+fn get<'a>(json: &'a Json, path: &[&str]) -> &'a Json {
+    path.iter().fold(json, |json, key| {
+        json.get(key)
+            .unwrap_or_else(|| panic!("Missing '{}' in {}", key, json))
+    })
+}
+
+fn at(line: u32, character: u32) -> Json {
+    Json::object([
+        ("textDocument", Json::object([("uri", URI.into())])),
+        (
+            "position",
+            Json::object([("line", line.into()), ("character", character.into())]),
+        ),
+    ])
+}
+
+fn document() -> Json {
+    Json::object([("textDocument", Json::object([("uri", URI.into())]))])
+}
+
+#[test]
+fn session() {
+    let mut client = Client::start();
+
+    let response = client.request(
+        "initialize",
+        Json::object([("capabilities", Json::object([]))]),
+    );
+    let capabilities = get(&response, &["result", "capabilities"]);
+    assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+    assert_eq!(
+        capabilities.get("definitionProvider"),
+        Some(&Json::Bool(true))
+    );
+    client.notify("initialized", Json::object([]));
+
+    client.notify(
+        "textDocument/didOpen",
+        Json::object([(
+            "textDocument",
+            Json::object([
+                ("uri", URI.into()),
+                ("languageId", "lox".into()),
+                ("version", 1u32.into()),
+                ("text", "print count;\n".into()),
+            ]),
+        )]),
+    );
+    let warnings = client.diagnostics();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        warnings[0].get("code"),
+        Some(&Json::from("undefined-global"))
+    );
+    assert_eq!(
+        get(&warnings[0], &["range", "start", "character"]),
+        &Json::from(6u32)
+    );
+
+    let errors = client.change(2, "var count = ;\n");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].get("severity"), Some(&Json::from(1u32)));
+    assert_eq!(
+        get(&errors[0], &["range", "start", "character"]),
+        &Json::from(12u32)
+    );
+
+    let source = "var count = 1;\n\n  var count = count + 1; // again\n\nprint count;\n";
+    assert_eq!(client.change(3, source).len(), 1);
+
+    let response = client.request("textDocument/semanticTokens/full", document());
+    let data = get(&response, &["result", "data"]).as_array().unwrap();
+    assert_eq!(data.len() % 5, 0);
+    // `var`, as a keyword
+    assert_eq!(&data[..5], &[0u32, 0, 3, 0, 0].map(Json::from));
+
+    // `count` on the last line is defined on line 3, the one in its initializer on line 1
+    let response = client.request("textDocument/definition", at(4, 7));
+    assert_eq!(
+        get(&response, &["result", "range", "start", "line"]),
+        &Json::from(2u32)
+    );
+    assert_eq!(get(&response, &["result", "uri"]), &Json::from(URI));
+    let response = client.request("textDocument/definition", at(2, 14));
+    assert_eq!(
+        get(&response, &["result", "range", "start", "line"]),
+        &Json::from(0u32)
+    );
+
+    let response = client.request("textDocument/hover", at(4, 6));
+    let hover = get(&response, &["result", "contents", "value"])
+        .as_str()
+        .unwrap();
+    assert!(hover.ends_with("Declared on lines 1, 3."), "{}", hover);
+    let response = client.request("textDocument/hover", at(4, 0));
+    assert_eq!(response.get("result"), Some(&Json::Null));
+
+    let response = client.request("textDocument/documentSymbol", document());
+    let symbols = get(&response, &["result"]).as_array().unwrap();
+    assert_eq!(symbols.len(), 2);
+    assert_eq!(
+        get(&symbols[1], &["selectionRange", "start", "character"]),
+        &Json::from(6u32)
+    );
+
+    let response = client.request("textDocument/rename", at(0, 4));
+    assert_eq!(get(&response, &["error", "code"]), &Json::Number(-32601.0));
+
+    let response = client.request("shutdown", Json::Null);
+    assert_eq!(response.get("result"), Some(&Json::Null));
+    assert_eq!(client.exit(), Some(0));
+}
+
+#[test]
+fn exit_without_shutdown() {
+    let mut client = Client::start();
+    let response = client.request("textDocument/hover", at(0, 0));
+    assert_eq!(get(&response, &["error", "code"]), &Json::Number(-32002.0));
+    assert_eq!(client.exit(), Some(1));
+}